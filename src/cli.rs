@@ -1,8 +1,8 @@
-use std::{borrow::BorrowMut, error::Error, io::Write, sync::Arc};
+use std::{error::Error, io::Write, net::SocketAddr, sync::Arc};
 
 use tokio::sync::Mutex;
 
-use crate::{id::Id, kbucket::KBUCKET_MAX_LENGTH, node::Node, rpc::RequestPayload};
+use crate::{id::Id, kbucket::KBUCKET_MAX_LENGTH, node::Node, reputation::Peer};
 
 /// Tracked `Runtime`
 pub struct Runtime {
@@ -23,13 +23,15 @@ impl Runtime {
         let node = Arc::new(Node::new(format!("{}:{}", ip, port)).await?);
         let nodes = Arc::clone(&self.nodes);
         tokio::spawn(async move {
-            let (h1, h2, h3) = node.start();
+            let handles = node.start();
             println!("{node}");
             {
                 let mut nodes = nodes.lock().await;
                 nodes.push(Arc::clone(&node));
             }
-            let _ = tokio::join!(h1, h2, h3);
+            for handle in handles {
+                let _ = handle.await;
+            }
         });
 
         Ok(())
@@ -37,7 +39,7 @@ impl Runtime {
     /// List active nodes
     pub async fn list(&self) {
         let nodes = self.nodes.lock().await;
-        let mut sep = ' ';
+        let sep = ' ';
         for n in nodes.iter() {
             // if self.selected.is_some() && self.selected.as_ref().unwrap() == &n.node_info.id {
             //     sep = 'x';
@@ -53,7 +55,11 @@ impl Runtime {
             print!("> ");
             std::io::stdout().flush()?;
             buffer.clear();
-            std::io::stdin().read_line(&mut buffer).unwrap();
+            // End of input exits like `exit`
+            if std::io::stdin().read_line(&mut buffer)? == 0 {
+                self.shutdown().await;
+                return Ok(());
+            }
             let args: Vec<&str> = buffer.trim().split(" ").collect();
             match args[0] {
                 // Spawn a new node
@@ -77,16 +83,41 @@ impl Runtime {
                 "ping" => {}
                 "find" => {}
                 "get" => {}
-                "" => {}
                 "history" => {}
+                // Ban or unban a peer by id or IP address on the selected node
+                "ban" | "unban" => {
+                    let peer = match args.get(1).and_then(|arg| Self::peer(arg)) {
+                        Some(peer) => peer,
+                        None => {
+                            println!("expected a node id or ip address");
+                            continue;
+                        }
+                    };
+                    self.ban(peer, args[0] == "ban").await;
+                }
                 "help" => {}
-                iv => {
+                // Shut every node down, snapshotting their routing tables, and leave the REPL
+                "exit" => {
+                    self.shutdown().await;
+                    return Ok(());
+                }
+                _ => {
                     println!("Invalid command");
                 }
             }
         }
     }
 
+    /// Shut down every spawned node
+    async fn shutdown(&self) {
+        let nodes = self.nodes.lock().await;
+        for n in nodes.iter() {
+            if let Err(e) = n.shutdown().await {
+                println!("failed to shut down {}: {}", n.node_info.id.hex(), e);
+            }
+        }
+    }
+
     async fn select(&mut self, id: Id) {
         let nodes = self.nodes.lock().await;
         for n in nodes.iter() {
@@ -98,20 +129,19 @@ impl Runtime {
         println!("unable to find node id");
     }
 
-    async fn ping(&mut self, id: Id) {
-        let mut node = match self.selected {
-            Some(ref node) => Arc::clone(&node),
+    async fn ping(&mut self, _id: Id) {
+        let node = match self.selected {
+            Some(ref node) => Arc::clone(node),
             None => {
                 println!("no node selected");
                 return;
             }
         };
 
-        let mut closest = Vec::new();
-        {
+        let _closest = {
             let router = node.router.lock().await;
-            closest = router.closest(&node.node_info.id, KBUCKET_MAX_LENGTH);
-        }
+            router.closest(&node.node_info.id, KBUCKET_MAX_LENGTH)
+        };
 
         // TODO: Make put each ping in a future
         // for n in closest {
@@ -119,8 +149,38 @@ impl Runtime {
         // }
     }
 
+    /// Parse a [`Peer`] from a hex [`Id`] or an IP address, also taking the IP address of an `ip:port`
+    fn peer(arg: &str) -> Option<Peer> {
+        if let Ok(ip) = arg.parse() {
+            return Some(Peer::address(ip));
+        }
+        if let Ok(address) = arg.parse::<SocketAddr>() {
+            return Some(Peer::address(address.ip()));
+        }
+
+        let is_id = arg.len() == 42
+            && arg.starts_with("0x")
+            && arg[2..].chars().all(|c| c.is_ascii_hexdigit());
+        is_id.then(|| Peer::Id(Id::from(arg)))
+    }
+
+    async fn ban(&mut self, peer: Peer, ban: bool) {
+        let node = match self.selected {
+            Some(ref node) => Arc::clone(node),
+            None => {
+                println!("no node selected");
+                return;
+            }
+        };
+
+        match ban {
+            true => node.ban(peer, None).await,
+            false => node.unban(&peer),
+        }
+    }
+
     /// Print the help dialog
     fn help() {
-        println!(r#""#)
+        println!()
     }
 }
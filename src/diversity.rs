@@ -0,0 +1,168 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Group of addresses likely to be controlled by the same party, an IPv4 /24 or an IPv6 /64
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub enum Subnet {
+    V4([u8; 3]),
+    V6([u8; 8]),
+}
+
+impl Subnet {
+    /// The `Subnet` of an IP address
+    pub fn new(ip: &IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(ip) => {
+                let [a, b, c, _] = ip.octets();
+                Subnet::V4([a, b, c])
+            }
+            IpAddr::V6(ip) => Subnet::V6(ip.octets()[..8].try_into().expect("ipv6 has 16 bytes")),
+        }
+    }
+}
+
+/// The address standing for a single host, an IPv4 address or the /64 of an IPv6 address as hosts are
+/// commonly handed a whole /64. IPv4 addresses mapped into IPv6 by a dual-stack socket are the IPv4 address they
+/// carry
+pub fn host(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & !(u64::MAX as u128))),
+        ip => ip,
+    }
+}
+
+/// A network in CIDR notation such as `10.0.0.0/8`
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub struct Network {
+    pub ip: IpAddr,
+    pub prefix: u8,
+}
+
+impl Network {
+    /// Check if `ip` is within the `Network`
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.ip, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ip, prefix) = s.split_once('/').ok_or("missing prefix length")?;
+        let ip: IpAddr = ip
+            .parse()
+            .map_err(|e: std::net::AddrParseError| e.to_string())?;
+        let prefix: u8 = prefix.parse().map_err(|_| "invalid prefix length")?;
+        let max = if ip.is_ipv4() { 32 } else { 128 };
+        if prefix > max {
+            return Err("prefix length too long".to_string());
+        }
+        Ok(Self { ip, prefix })
+    }
+}
+
+/// Limits on how many nodes in a [`crate::routing::RoutingTable`] may share a [`Subnet`], so a single party
+/// cannot fill our buckets and eclipse us
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiversityLimits {
+    /// Nodes from the same [`Subnet`] allowed in one [`crate::kbucket::KBucket`]
+    pub per_bucket: usize,
+    /// Nodes from the same [`Subnet`] allowed in the whole table
+    pub per_table: usize,
+    /// Networks exempt from the limits, such as a local network every node runs in
+    pub allowlist: Vec<Network>,
+}
+
+impl Default for DiversityLimits {
+    fn default() -> Self {
+        Self {
+            per_bucket: 2,
+            per_table: 10,
+            allowlist: vec![
+                Network {
+                    ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    prefix: 8,
+                },
+                Network {
+                    ip: IpAddr::V6(Ipv6Addr::LOCALHOST),
+                    prefix: 128,
+                },
+            ],
+        }
+    }
+}
+
+impl DiversityLimits {
+    /// The [`Subnet`] an `ip:port` address is limited by, `None` when it is allowlisted. Addresses that are not
+    /// an IP address, such as hostnames, are refused as the limits could not be applied to them. IPv4 addresses
+    /// mapped into IPv6 by a dual-stack socket are limited as the IPv4 address they carry
+    pub fn subnet(&self, address: &str) -> Result<Option<Subnet>, String> {
+        let ip = address
+            .parse::<SocketAddr>()
+            .map_err(|_| format!("{} is not an ip:port address", address))?
+            .ip()
+            .to_canonical();
+        match self.allowlist.iter().any(|network| network.contains(&ip)) {
+            true => Ok(None),
+            false => Ok(Some(Subnet::new(&ip))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn subnet() {
+        let limits = DiversityLimits::default();
+        assert_eq!(
+            limits.subnet("1.2.3.4:8080"),
+            limits.subnet("1.2.3.200:8081")
+        );
+        assert_ne!(limits.subnet("1.2.3.4:8080"), limits.subnet("1.2.4.4:8080"));
+        assert_eq!(
+            limits.subnet("[2001:db8::1]:8080"),
+            limits.subnet("[2001:db8::ffff:1]:8080")
+        );
+        assert_eq!(
+            limits.subnet("1.2.3.4:8080"),
+            limits.subnet("[::ffff:1.2.3.4]:8080")
+        );
+        assert_ne!(
+            limits.subnet("[::ffff:1.2.3.4]:8080"),
+            limits.subnet("[::ffff:5.6.7.8]:8080")
+        );
+        assert_eq!(limits.subnet("127.0.0.1:8080"), Ok(None));
+        assert_eq!(limits.subnet("[::ffff:127.0.0.1]:8080"), Ok(None));
+        assert!(limits.subnet("localhost:8080").is_err());
+    }
+
+    #[test]
+    fn network() {
+        let network: Network = "10.0.0.0/8".parse().unwrap();
+        assert!(network.contains(&"10.200.0.1".parse().unwrap()));
+        assert!(!network.contains(&"11.0.0.1".parse().unwrap()));
+        assert!(!network.contains(&"::1".parse().unwrap()));
+
+        let any: Network = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains(&"8.8.8.8".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<Network>().is_err());
+        assert!("10.0.0.0".parse::<Network>().is_err());
+    }
+}
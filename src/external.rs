@@ -0,0 +1,92 @@
+use std::{collections::VecDeque, net::SocketAddr};
+
+use crate::diversity::Subnet;
+
+/// Votes an observed address needs before it is believed
+pub const MIN_VOTES: usize = 3;
+
+/// Most votes remembered, older ones are forgotten as our address may change
+pub const MAX_VOTES: usize = 32;
+
+/// Tallies the addresses peers observed our requests coming from, to learn the address we are reachable at
+/// from behind NAT or a container
+#[derive(Debug, Default)]
+pub struct ExternalAddress {
+    /// Latest vote from each [`Subnet`], oldest first
+    votes: VecDeque<(Subnet, SocketAddr)>,
+    /// Address agreed on by the votes so far
+    learned: Option<SocketAddr>,
+}
+
+impl ExternalAddress {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the address a peer in the `voter` [`Subnet`] observed us at, returning the address if this vote
+    /// changed which one is agreed on. Each subnet only has a single vote, as [`crate::id::Id`]s are cheap to
+    /// make but addresses in many subnets are not
+    pub fn vote(&mut self, voter: Subnet, observed: SocketAddr) -> Option<SocketAddr> {
+        self.votes.retain(|(subnet, _)| subnet != &voter);
+        self.votes.push_back((voter, observed));
+        if self.votes.len() > MAX_VOTES {
+            self.votes.pop_front();
+        }
+
+        let winner = self.winner();
+        if winner.is_none() || winner == self.learned {
+            return None;
+        }
+        self.learned = winner;
+        winner
+    }
+
+    /// The address agreed on, `None` until one has a majority of at least [`MIN_VOTES`]
+    pub fn learned(&self) -> Option<SocketAddr> {
+        self.learned
+    }
+
+    /// The address with a majority of the votes and at least [`MIN_VOTES`]
+    fn winner(&self) -> Option<SocketAddr> {
+        let (address, count) = self
+            .votes
+            .iter()
+            .map(|(_, address)| {
+                let count = self.votes.iter().filter(|(_, a)| a == address).count();
+                (*address, count)
+            })
+            .max_by_key(|(_, count)| *count)?;
+
+        match count >= MIN_VOTES && count * 2 > self.votes.len() {
+            true => Some(address),
+            false => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn vote() {
+        let mut external = ExternalAddress::new();
+        let public: SocketAddr = "1.2.3.4:8080".parse().unwrap();
+        let other: SocketAddr = "5.6.7.8:8080".parse().unwrap();
+
+        let subnet = |ip: &str| Subnet::new(&ip.parse().unwrap());
+
+        // Peers in a single subnet voting repeatedly are only counted once
+        for i in 0..MIN_VOTES {
+            let voter = subnet(&format!("9.9.9.{}", i));
+            assert_eq!(external.vote(voter, public), None);
+        }
+        assert_eq!(external.learned(), None);
+
+        assert_eq!(external.vote(subnet("10.0.0.1"), other), None);
+        assert_eq!(external.vote(subnet("10.0.1.1"), public), None);
+        assert_eq!(external.vote(subnet("10.0.2.1"), public), Some(public));
+        assert_eq!(external.vote(subnet("10.0.3.1"), public), None);
+        assert_eq!(external.learned(), Some(public));
+    }
+}
@@ -1,12 +1,29 @@
 use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
 use std::fmt::{Debug, Error, Formatter, Write};
 
 /// Number of bytes in an `Id`
 pub const ID_SIZE: usize = 20;
 
-/// Node identification
-#[derive(PartialEq, Eq, Serialize, Deserialize, Clone, Hash)]
+/// S/Kademlia crypto puzzles an `Id` must solve, in leading zero bits, to make generating many `Ids` costly
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Difficulty {
+    /// Leading zero bits of the hash of the `Id`, only met by trying key pairs
+    pub static_bits: usize,
+    /// Leading zero bits of the hash of the `Id` XORed with a solution found per `Id`
+    pub dynamic_bits: usize,
+}
+
+impl Difficulty {
+    /// Check that `id` solves the static puzzle and `solution` solves the dynamic puzzle for it
+    pub fn check(&self, id: &Id, solution: &Id) -> bool {
+        id.static_puzzle() >= self.static_bits && id.dynamic_puzzle(solution) >= self.dynamic_bits
+    }
+}
+
+/// Node identification. Ordering is numeric, so comparing [`Id::xor`] results compares true XOR distances
+#[derive(PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Clone, Hash)]
 pub struct Id([u8; ID_SIZE]);
 
 impl Id {
@@ -15,11 +32,30 @@ impl Id {
         Id(xs)
     }
 
+    /// The raw bytes of the `Id`
+    pub fn as_bytes(&self) -> &[u8; ID_SIZE] {
+        &self.0
+    }
+
     /// Create a new `Id` from [`rand::thread_rng`]
     pub fn random() -> Self {
         Id(thread_rng().gen::<[u8; ID_SIZE]>())
     }
 
+    /// Create the `Id` a key is stored under by hashing it into the id space
+    pub fn from_key(key: &[u8]) -> Self {
+        Id(Sha1::digest(key).into())
+    }
+
+    /// The full XOR of two `Ids`, where a smaller result means the `Ids` are closer
+    pub fn xor(&self, x: &Self) -> Self {
+        let mut xs = [0u8; ID_SIZE];
+        for (i, (a, b)) in self.0.iter().zip(x.0.iter()).enumerate() {
+            xs[i] = a ^ b;
+        }
+        Id(xs)
+    }
+
     /// Find the XOR distance between two `Ids` via the number of prefix zero bits
     pub fn distance(&self, x: &Self) -> usize {
         Id(self
@@ -33,6 +69,26 @@ impl Id {
         .leading_zeros()
     }
 
+    /// Leading zero bits of the static puzzle, the hash of the `Id`
+    pub fn static_puzzle(&self) -> usize {
+        Id::from_key(&self.0).leading_zeros()
+    }
+
+    /// Leading zero bits of the dynamic puzzle, the hash of the `Id` XORed with `solution`
+    pub fn dynamic_puzzle(&self, solution: &Id) -> usize {
+        Id::from_key(&self.xor(solution).0).leading_zeros()
+    }
+
+    /// Find a solution to the dynamic puzzle with at least `bits` leading zero bits
+    pub fn solve(&self, bits: usize) -> Id {
+        loop {
+            let solution = Id::random();
+            if self.dynamic_puzzle(&solution) >= bits {
+                return solution;
+            }
+        }
+    }
+
     /// Number of prefix zero bits between two `Ids`
     pub fn leading_zeros(&self) -> usize {
         for i in 0..20 {
@@ -96,6 +152,19 @@ mod test {
         assert!((x.distance(&y) + y.distance(&z)) >= x.distance(&z));
     }
 
+    #[test]
+    fn xor() {
+        let x = Id::new([1u8; 20]);
+        let y = Id::new([4u8; 20]);
+        let z = Id::new([5u8; 20]);
+        assert_eq!(x.xor(&x), Id::new([0u8; 20]));
+        assert_eq!(x.xor(&y), Id::new([5u8; 20]));
+        assert!(z.xor(&y) < z.xor(&x));
+        assert_eq!(x.xor(&y).leading_zeros(), x.distance(&y));
+        assert_eq!(Id::from_key(b"hello"), Id::from_key(b"hello"));
+        assert_ne!(Id::from_key(b"hello"), Id::from_key(b"world"));
+    }
+
     #[test]
     fn leading_zeros() {
         assert_eq!(Id([0; 20]).leading_zeros(), ID_SIZE * 8);
@@ -110,6 +179,33 @@ mod test {
         assert_eq!(Id(xs).leading_zeros(), 5 * 8);
     }
 
+    #[test]
+    fn puzzles() {
+        let id = Id::random();
+        let solution = id.solve(8);
+        assert!(id.dynamic_puzzle(&solution) >= 8);
+
+        let difficulty = Difficulty {
+            static_bits: 0,
+            dynamic_bits: 8,
+        };
+        assert!(difficulty.check(&id, &solution));
+        assert_eq!(
+            difficulty.check(&id, &Id::new([0; 20])),
+            id.dynamic_puzzle(&Id::new([0; 20])) >= 8
+        );
+
+        let id = (0..)
+            .map(|_| Id::random())
+            .find(|id| id.static_puzzle() >= 4)
+            .unwrap();
+        assert!(Difficulty {
+            static_bits: 4,
+            dynamic_bits: 0,
+        }
+        .check(&id, &Id::random()));
+    }
+
     #[test]
     fn hex() {
         let x = Id::new([1u8; 20]);
@@ -1,11 +1,13 @@
 use std::collections::VecDeque;
 
+use serde::{Deserialize, Serialize};
+
 use crate::{id::Id, routing::NodeInfo};
 
 /// Maximum length of a `KBucket` before it is required to be split
 pub const KBUCKET_MAX_LENGTH: usize = 20;
 
-#[derive(Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct KBucket(pub VecDeque<NodeInfo>);
 
 impl KBucket {
@@ -13,15 +15,11 @@ impl KBucket {
         KBucket(VecDeque::new())
     }
 
-    /// Upsert a [`NodeInfo`] into the `KBucket`. Moving existing values to the tail
+    /// Upsert a [`NodeInfo`] into the `KBucket`. Moving existing values to the tail, replacing the address of a
+    /// node already in the `KBucket` if it changed
     pub fn upsert(&mut self, x: NodeInfo) {
-        if self.0.contains(&x) {
-            self.0.remove(
-                self.0
-                    .iter()
-                    .position(|y| *y == x)
-                    .expect("node info needle not found"),
-            );
+        if let Some(i) = self.0.iter().position(|y| y.id == x.id) {
+            self.0.remove(i);
         }
 
         self.0.push_back(x);
@@ -35,7 +33,7 @@ impl KBucket {
         self.0
             .iter()
             .position(|y| &y.id == id)
-            .and_then(|i| self.0.get(i).map(|x| x.clone()))
+            .and_then(|i| self.0.get(i).cloned())
     }
 
     /// Check if the [`NodeInfo`] is contained within the `KBucket`
@@ -43,13 +41,12 @@ impl KBucket {
         self.0.iter().any(|y| y == x)
     }
 
-    /// Remove a [`NodeInfo`] from the `KBucket`
+    /// Remove the node with the same [`Id`] as a [`NodeInfo`] from the `KBucket`
     pub fn remove(&mut self, x: &NodeInfo) -> Option<NodeInfo> {
         self.0
             .iter()
-            .position(|y| y == x)
-            .map(|y| self.0.remove(y))
-            .flatten()
+            .position(|y| y.id == x.id)
+            .and_then(|y| self.0.remove(y))
     }
 
     /// Split the `KBucket` at the given `distance`, returning a new `KBucket` which contain nodes further away than the distance
@@ -75,14 +72,8 @@ mod test {
     #[test]
     fn it_works() {
         let mut kb: KBucket = KBucket::new();
-        let x = NodeInfo {
-            id: Id::random(),
-            address: "localhost:8080".to_string(),
-        };
-        let y = NodeInfo {
-            id: Id::random(),
-            address: "localhost:8081".to_string(),
-        };
+        let x = NodeInfo::with_id(Id::random(), "localhost:8080".to_string());
+        let y = NodeInfo::with_id(Id::random(), "localhost:8081".to_string());
         kb.upsert(x.clone());
         assert_eq!(kb.0.len(), 1);
         kb.upsert(x.clone());
@@ -91,6 +82,13 @@ mod test {
         assert_eq!(kb.0, vec![x.clone(), y.clone()]);
         kb.upsert(x.clone());
         assert_eq!(kb.0, vec![y.clone(), x.clone()]);
+
+        let moved = NodeInfo {
+            address: "localhost:8082".to_string(),
+            ..x.clone()
+        };
+        kb.upsert(moved.clone());
+        assert_eq!(kb.0, vec![y.clone(), moved]);
         kb.remove(&x);
         assert_eq!(kb.0, vec![y]);
     }
@@ -99,10 +97,7 @@ mod test {
     fn find() {
         let mut kb: KBucket = KBucket::new();
         let id = Id::random();
-        let x = NodeInfo {
-            id: id.clone(),
-            address: "localhost:8080".to_string(),
-        };
+        let x = NodeInfo::with_id(id.clone(), "localhost:8080".to_string());
         kb.upsert(x.clone());
         assert_eq!(kb.find(&id), Some(x.clone()));
     }
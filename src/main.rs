@@ -2,12 +2,20 @@
 #![allow(dead_code)]
 
 mod cli;
+mod diversity;
+mod external;
 mod id;
 mod kbucket;
 mod node;
+mod providers;
+mod ratelimit;
+mod records;
+mod reputation;
 mod routing;
 mod rpc;
+mod session;
 mod storage;
+mod validation;
 
 #[tokio::main]
 async fn main() {
@@ -21,8 +29,8 @@ async fn main() {
         .await
         .unwrap();
 
-    let (rh1, ph1, remh1) = n1.start();
-    let (rh2, ph2, remh2) = n2.start();
+    let mut handles = n1.start();
+    handles.extend(n2.start());
 
     let res1 = n1.send(rpc::RequestPayload::Ping, &n2.node_info).await;
     let res2 = n2.send(rpc::RequestPayload::Ping, &n1.node_info).await;
@@ -33,8 +41,11 @@ async fn main() {
     let res3 = n1
         .send(
             rpc::RequestPayload::Store {
-                key: "hello".to_string(),
-                value: "world".to_string(),
+                key: id::Id::from_key(b"hello"),
+                value: b"world".to_vec(),
+                ttl: None,
+                namespace: None,
+                cached: false,
             },
             &n2.node_info,
         )
@@ -42,7 +53,7 @@ async fn main() {
 
     println!("res3: {:?}", res3);
 
-    let res4 = n2.store.lock().await.get(&"hello".to_string());
+    let res4 = n2.store.lock().await.get(&id::Id::from_key(b"hello"));
     println!("res4: {:?}", res4);
 
     let res5 = n1
@@ -56,5 +67,16 @@ async fn main() {
 
     println!("res5: {:?}", res5);
 
-    let _ = tokio::join!(rh1, ph1, remh1, rh2, ph2, remh2);
+    // Stop on ctrl-c, snapshotting each node's routing table on the way out
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        eprintln!("failed to listen for ctrl-c: {}", e);
+    }
+    for node in [&n1, &n2] {
+        if let Err(e) = node.shutdown().await {
+            eprintln!("failed to shut down {}: {}", node.node_info.address, e);
+        }
+    }
+    for handle in handles {
+        let _ = handle.await;
+    }
 }
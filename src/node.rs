@@ -1,49 +1,325 @@
-use std::{collections::HashMap, error::Error, fmt::Display, sync::Arc, time::Duration};
+use std::{
+    cmp,
+    collections::{HashMap, HashSet, VecDeque},
+    error::Error,
+    fmt::Display,
+    io,
+    net::SocketAddr,
+    path::PathBuf,
+    slice,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
+use ed25519_dalek::{SigningKey, PUBLIC_KEY_LENGTH, SECRET_KEY_LENGTH};
+use rand::rngs::OsRng;
+use serde::{de::DeserializeOwned, Serialize};
 use tokio::{
+    fs,
+    io::AsyncWriteExt,
     sync::{
         mpsc::{self},
-        oneshot::{self, Sender},
-        Mutex,
+        oneshot::{self},
+        watch, Mutex,
     },
     task::JoinHandle,
     time::{self, timeout},
 };
 
 use crate::{
-    id::Id,
+    diversity::DiversityLimits,
+    external::ExternalAddress,
+    id::{Difficulty, Id, ID_SIZE},
     kbucket::KBUCKET_MAX_LENGTH,
-    routing::{NodeInfo, RoutingTable},
-    rpc::{Message, RequestHandle, RequestPayload, ResponseHandle, ResponsePayload, Rpc},
-    storage::Store,
+    providers::{ProviderLimits, Providers, MAX_PROVIDERS, PROVIDER_TTL},
+    ratelimit::RateLimits,
+    records::{
+        ImmutableValidator, MutableRecord, MutableValidator, IMMUTABLE_NAMESPACE, MUTABLE_NAMESPACE,
+    },
+    reputation::{Offence, Peer, Reputation, SharedReputation, BAN_DURATION, BAN_THRESHOLD},
+    routing::{NodeInfo, RoutingTable, Snapshot},
+    rpc::{
+        self, Message, RequestHandle, RequestKind, RequestPayload, ResponseHandle, ResponsePayload,
+        Rpc,
+    },
+    storage::{scaled_ttl, FileBackend, Record, Rejection, Store, StoreLimits, STALE_DURATION},
+    validation::Validators,
 };
 
 /// Time to wait for a response before timing out
 const RESPONSE_TIMEOUT: Duration = Duration::new(1, 0);
 
+/// Number of requests a lookup keeps in flight at once
+pub const ALPHA: usize = 3;
+
+/// Most contacts held while waiting to verify their address, the oldest is dropped to make room
+pub const MAX_UNVERIFIED: usize = 256;
+
+/// How often unverified contacts are pinged
+pub const VERIFY_INTERVAL: Duration = Duration::new(10, 0);
+
+/// Number of values a namespaced lookup collects for its validator to select between
+pub const RECORD_QUORUM: usize = 3;
+
+/// Tunable behaviour of a [`Node`]
+#[derive(Debug, Clone)]
+pub struct NodeConfig {
+    /// Where the [`RoutingTable`] is snapshotted to, snapshots are disabled when `None`
+    pub snapshot_path: Option<PathBuf>,
+    /// Where the node's secret key and the solution of its dynamic puzzle are kept, a new key and so a new [`Id`]
+    /// is generated on every start when `None`
+    pub identity_path: Option<PathBuf>,
+    /// Only talk to peers over encrypted sessions, peers are otherwise only sent encrypted messages once they
+    /// start a session themselves
+    pub encrypt: bool,
+    /// Crypto puzzles our [`Id`] is generated to solve, and that other nodes must solve to be contacted
+    pub difficulty: Difficulty,
+    /// Number of disjoint paths a lookup is split into, S/Kademlia suggests a handful when some nodes may be
+    /// malicious
+    pub disjoint_paths: usize,
+    /// Limits on contacts sharing an IPv4 /24 or IPv6 /64 in the [`RoutingTable`]
+    pub diversity: DiversityLimits,
+    /// Score below which a misbehaving peer is banned
+    pub ban_threshold: i32,
+    /// Time a peer is banned for
+    pub ban_duration: Duration,
+    /// Datagrams per second received from a single IP address and requests per second handled from everyone together
+    pub rate_limits: RateLimits,
+    /// How often the [`RoutingTable`] is snapshotted while running
+    pub snapshot_interval: Duration,
+    /// Log file for a durable [`Store`], records are only kept in memory when `None`
+    pub storage_path: Option<PathBuf>,
+    /// Lifetime of a stored record when the publisher does not ask for one
+    pub default_record_ttl: Duration,
+    /// Upper bound on the lifetime a publisher can ask for
+    pub max_record_ttl: Duration,
+    /// How often records this node published are pushed again to the k closest nodes, shorter than the ttl they
+    /// are published with so they are refreshed before they expire
+    pub republish_interval: Duration,
+    /// How often every stored record is pushed to the nodes currently closest to its key
+    pub replicate_interval: Duration,
+    /// Lifetime of a value cached along a lookup path, before it is scaled by distance to the key
+    pub cache_ttl: Duration,
+    /// How long a provider announcement is remembered by the nodes receiving it
+    pub provider_ttl: Duration,
+    /// How often keys this node provides are announced again
+    pub provider_republish_interval: Duration,
+    /// Bounds on the records accepted from other nodes
+    pub store_limits: StoreLimits,
+    /// Bounds on the provider announcements accepted from other nodes
+    pub provider_limits: ProviderLimits,
+    /// Validators for namespaced records, records in a namespace without one are rejected
+    pub validators: Validators,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        Self {
+            snapshot_path: None,
+            identity_path: None,
+            encrypt: false,
+            difficulty: Difficulty::default(),
+            disjoint_paths: 1,
+            diversity: DiversityLimits::default(),
+            ban_threshold: BAN_THRESHOLD,
+            ban_duration: BAN_DURATION,
+            rate_limits: RateLimits::default(),
+            snapshot_interval: Duration::from_secs(10 * 60),
+            storage_path: None,
+            default_record_ttl: STALE_DURATION,
+            max_record_ttl: 7 * STALE_DURATION,
+            republish_interval: Duration::from_secs(22 * 60 * 60),
+            replicate_interval: Duration::from_secs(60 * 60),
+            cache_ttl: Duration::from_secs(60 * 60),
+            provider_ttl: PROVIDER_TTL,
+            provider_republish_interval: PROVIDER_TTL / 2,
+            store_limits: StoreLimits::default(),
+            provider_limits: ProviderLimits::default(),
+            validators: {
+                let mut validators = Validators::new();
+                validators.register(MUTABLE_NAMESPACE, Arc::new(MutableValidator));
+                validators.register(IMMUTABLE_NAMESPACE, Arc::new(ImmutableValidator));
+                validators
+            },
+        }
+    }
+}
+
+/// A record this node is the original publisher of, along with the ttl it was published with
+#[derive(Debug, Clone)]
+pub struct Published {
+    pub value: Vec<u8>,
+    pub ttl: Option<Duration>,
+    pub namespace: Option<String>,
+}
+
+/// A value found by a lookup along with the namespace it was stored in
+type Found = (Vec<u8>, Option<String>);
+
+/// Outcome of an iterative lookup
+#[derive(Default)]
+struct Lookup {
+    /// Closest nodes to the target that responded without a value, closest first
+    closest: Vec<NodeInfo>,
+    /// Values found, those found by the most paths first
+    values: Vec<Found>,
+    /// The value found by more paths than any other, `None` when the paths disagree
+    agreed: Option<Found>,
+    providers: Vec<NodeInfo>,
+}
+
+impl Lookup {
+    /// Merge the lookups of disjoint paths to `id`. Paths take turns adding their closest nodes, so a path led
+    /// astray by malicious nodes fills at most its share of the result however close the nodes it claims are
+    fn merge(id: &Id, lookups: Vec<Lookup>) -> Self {
+        let mut merged = Lookup::default();
+        let mut found: Vec<(Found, usize)> = Vec::new();
+        let mut paths = Vec::new();
+        for lookup in lookups {
+            // A path only counts once towards each value it found
+            for (i, value) in lookup.values.iter().enumerate() {
+                if lookup.values[..i].contains(value) {
+                    continue;
+                }
+                match found.iter_mut().find(|(v, _)| v == value) {
+                    Some((_, count)) => *count += 1,
+                    None => found.push((value.clone(), 1)),
+                }
+            }
+            for n in lookup.providers {
+                if !merged.providers.iter().any(|p| p.id == n.id) {
+                    merged.providers.push(n);
+                }
+            }
+            paths.push(lookup.closest.into_iter());
+        }
+
+        while merged.closest.len() < KBUCKET_MAX_LENGTH {
+            let mut added = false;
+            for path in paths.iter_mut() {
+                for n in path.by_ref() {
+                    if !merged.closest.iter().any(|c| c.id == n.id) {
+                        merged.closest.push(n);
+                        added = true;
+                        break;
+                    }
+                }
+            }
+            if !added {
+                break;
+            }
+        }
+        merged.closest.truncate(KBUCKET_MAX_LENGTH);
+        merged.closest.sort_by_key(|n| n.id.xor(id));
+
+        found.sort_by_key(|(_, count)| cmp::Reverse(*count));
+        merged.agreed = match found.as_slice() {
+            [(value, _)] => Some(value.clone()),
+            [(value, first), (_, second), ..] if first > second => Some(value.clone()),
+            _ => None,
+        };
+        merged.values = found.into_iter().map(|(value, _)| value).collect();
+        merged
+    }
+}
+
+/// A request waiting on its response
+pub struct Pending {
+    /// Node the request was sent to, only it may answer
+    pub destination: Id,
+    /// Address the request was sent to, the response must come from it
+    pub address: SocketAddr,
+    /// Kind of the request, which limits the kinds of response accepted
+    pub kind: RequestKind,
+    pub tx: oneshot::Sender<ResponsePayload>,
+}
+
 #[derive(Clone)]
 pub struct Node {
     pub node_info: NodeInfo,
     pub router: Arc<Mutex<RoutingTable>>,
-    pub store: Arc<Mutex<Store<String, String>>>,
-    pub pending: Arc<Mutex<HashMap<Id, oneshot::Sender<ResponsePayload>>>>,
+    pub store: Arc<Mutex<Store<Id, Vec<u8>>>>,
+    pub pending: Arc<Mutex<HashMap<Id, Pending>>>,
+    pub published: Arc<Mutex<HashMap<Id, Published>>>,
+    pub providers: Arc<Mutex<Providers>>,
+    /// Keys this node announces itself as a provider of
+    pub providing: Arc<Mutex<HashSet<Id>>>,
+    /// Contacts learned second-hand, only added to the [`RoutingTable`] once they answer a ping from the address
+    /// they were given with
+    pub unverified: Arc<Mutex<VecDeque<NodeInfo>>>,
+    /// Addresses peers observed our requests coming from, used to learn the address we are reachable at
+    pub external: Arc<Mutex<ExternalAddress>>,
     pub rpc: Arc<Rpc>,
+    pub config: Arc<NodeConfig>,
+    /// Key pair the node's [`Id`] is derived from
+    signing_key: Arc<SigningKey>,
+    /// Scores of misbehaving peers and the peers that are banned
+    pub reputation: SharedReputation,
+    shutdown: Arc<watch::Sender<bool>>,
 }
 
 impl Node {
-    /// Create a new node with a random [`Id`] and an empty [`RoutingTable`], start the [`Rpc`]
+    /// Create a new node with a fresh key pair and an empty [`RoutingTable`], start the [`Rpc`]
     pub async fn new(address: String) -> Result<Self, Box<dyn Error>> {
-        let id = Id::random();
+        Self::with_config(address, NodeConfig::default()).await
+    }
+
+    /// Create a new node from a [`NodeConfig`]. When a snapshot exists at [`NodeConfig::snapshot_path`] the
+    /// node keeps its previous [`RoutingTable`], contacts are verified once started. The [`Id`] is only kept
+    /// across restarts when [`NodeConfig::identity_path`] is set
+    pub async fn with_config(address: String, config: NodeConfig) -> Result<Self, Box<dyn Error>> {
+        let restored = match config.snapshot_path {
+            Some(ref path) => Self::load_snapshot(path).await?,
+            None => None,
+        };
 
-        let node_info = NodeInfo {
-            id: id,
-            address: address.clone(),
+        let (signing_key, puzzle) = match config.identity_path {
+            Some(ref path) => Self::load_identity(path, config.difficulty).await?,
+            None => Self::generate_identity(config.difficulty),
         };
+        let mut node_info = NodeInfo::new(&signing_key.verifying_key(), address.clone());
+        node_info.puzzle = puzzle;
         let socket = tokio::net::UdpSocket::bind(&address).await?;
-        let store = Arc::new(Mutex::new(Store::new()));
-        let router = Arc::new(Mutex::new(RoutingTable::new(node_info.clone())));
-        let rpc = Arc::new(Rpc::new(Arc::new(socket)));
+        let store = match config.storage_path {
+            Some(ref path) => Store::with_backend(Box::new(FileBackend::open(path)?)),
+            None => Store::new(),
+        };
+        let store = Arc::new(Mutex::new(store));
+        let reputation = Arc::new(std::sync::Mutex::new(Reputation::new(
+            config.ban_threshold,
+            config.ban_duration,
+        )));
+        let table = RoutingTable::with_limits(
+            node_info.clone(),
+            config.difficulty,
+            config.diversity.clone(),
+            Arc::clone(&reputation),
+        );
+        let unverified: VecDeque<NodeInfo> = restored
+            .map(|snapshot| snapshot.contacts())
+            .unwrap_or_default()
+            .into_iter()
+            .filter(NodeInfo::verify)
+            .take(MAX_UNVERIFIED)
+            .collect();
+        let router = Arc::new(Mutex::new(table));
+        let signing_key = Arc::new(signing_key);
+        let rpc = Arc::new(Rpc::new(
+            Arc::new(socket),
+            Arc::clone(&signing_key),
+            config.encrypt,
+            Arc::clone(&reputation),
+            config.rate_limits,
+        ));
         let pending = Arc::new(Mutex::new(HashMap::new()));
+        let published = Arc::new(Mutex::new(HashMap::new()));
+        let providers = Arc::new(Mutex::new(Providers::with_limits(
+            config.provider_limits.clone(),
+        )));
+        let providing = Arc::new(Mutex::new(HashSet::new()));
+        let unverified = Arc::new(Mutex::new(unverified));
+        let external = Arc::new(Mutex::new(ExternalAddress::new()));
+        let (shutdown, _) = watch::channel(false);
 
         Ok(Self {
             node_info,
@@ -51,33 +327,59 @@ impl Node {
             router,
             store,
             pending,
+            published,
+            providers,
+            providing,
+            unverified,
+            external,
+            config: Arc::new(config),
+            signing_key,
+            reputation,
+            shutdown: Arc::new(shutdown),
         })
     }
 
     /// Start receive and process services
-    pub fn start(&self) -> (JoinHandle<()>, JoinHandle<()>, JoinHandle<()>) {
+    pub fn start(&self) -> Vec<JoinHandle<()>> {
         let (tx, rx) = mpsc::channel(50);
-        let receive_handle = self.rpc.receive(tx);
-        let process_handle = self.process(rx);
-        let remover_handle = self.remover();
-        (receive_handle, process_handle, remover_handle)
+        vec![
+            self.rpc.receive(tx, self.shutdown.subscribe()),
+            self.process(rx),
+            self.remover(),
+            self.snapshotter(),
+            self.verifier(),
+            self.republisher(),
+            self.replicator(),
+            self.announcer(),
+        ]
+    }
+
+    /// Stop all services, taking a final snapshot of the [`RoutingTable`]
+    pub async fn shutdown(&self) -> io::Result<()> {
+        self.shutdown.send_replace(true);
+        self.save_snapshot().await
     }
 
     /// Process incoming messages
-    fn process(&self, mut rx: mpsc::Receiver<Message>) -> JoinHandle<()> {
+    fn process(&self, mut rx: mpsc::Receiver<(Message, SocketAddr)>) -> JoinHandle<()> {
         // This might panic as its a mutable reference while main thread is doing shit
         let mut node = self.clone();
+        let mut shutdown = self.shutdown.subscribe();
         let process_handle = tokio::spawn(async move {
             loop {
-                for message in rx.recv().await {
-                    match message {
-                        Message::Request(request_handle) => {
-                            node.process_request(request_handle).await
-                        }
-                        Message::Response(response_handle) => {
-                            node.process_response(response_handle).await
-                        }
+                let message = tokio::select! {
+                    message = rx.recv() => message,
+                    _ = shutdown.changed() => None,
+                };
+
+                match message {
+                    Some((Message::Request(request_handle), address)) => {
+                        node.process_request(request_handle, address).await
                     }
+                    Some((Message::Response(response_handle), address)) => {
+                        node.process_response(response_handle, address).await
+                    }
+                    None => break,
                 }
             }
         });
@@ -87,117 +389,1184 @@ impl Node {
 
     /// Start the service to remove stale indexes every hour
     pub fn remover(&self) -> JoinHandle<()> {
-        let store = Arc::clone(&self.store);
+        let node = self.clone();
+        let mut shutdown = self.shutdown.subscribe();
         let remover_handle = tokio::spawn(async move {
             let mut interval = time::interval(Duration::from_secs(60 * 60));
             loop {
+                if let Err(e) = node.write_store(|store| store.remove_stale()).await {
+                    eprintln!("failed to remove stale records: {}", e);
+                }
                 {
-                    let mut store = store.lock().await;
-                    store.remove_stale();
+                    let mut providers = node.providers.lock().await;
+                    providers.remove_stale();
+                }
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = shutdown.changed() => break,
                 }
-                interval.tick().await;
             }
         });
 
         remover_handle
     }
 
-    /// Handle a request and send a response
-    async fn process_request(&mut self, message: RequestHandle) {
+    /// Start the service to snapshot the [`RoutingTable`] every [`NodeConfig::snapshot_interval`]
+    pub fn snapshotter(&self) -> JoinHandle<()> {
+        let node = self.clone();
+        let mut shutdown = self.shutdown.subscribe();
+        tokio::spawn(async move {
+            if node.config.snapshot_path.is_none() {
+                return;
+            }
+
+            let mut interval = time::interval(node.config.snapshot_interval);
+            interval.tick().await;
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = shutdown.changed() => break,
+                }
+                if let Err(e) = node.save_snapshot().await {
+                    eprintln!("failed to snapshot routing table: {}", e);
+                }
+            }
+        })
+    }
+
+    /// Start the service to republish owned records every [`NodeConfig::republish_interval`]
+    pub fn republisher(&self) -> JoinHandle<()> {
+        let node = self.clone();
+        let mut shutdown = self.shutdown.subscribe();
+        tokio::spawn(async move {
+            let mut interval = time::interval(node.config.republish_interval);
+            interval.tick().await;
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = shutdown.changed() => break,
+                }
+
+                let published: Vec<_> = {
+                    let published = node.published.lock().await;
+                    published
+                        .iter()
+                        .map(|(k, p)| (k.clone(), p.clone()))
+                        .collect()
+                };
+                for (key, published) in published {
+                    node.publish(key, published).await;
+                }
+            }
+        })
+    }
+
+    /// Start the service to replicate stored records to the k closest nodes every [`NodeConfig::replicate_interval`].
+    /// Only records we are among the k closest to are replicated, and not cached copies or records another node
+    /// refreshed within the interval, as it replicated them already
+    pub fn replicator(&self) -> JoinHandle<()> {
+        let node = self.clone();
+        let mut shutdown = self.shutdown.subscribe();
+        tokio::spawn(async move {
+            let mut interval = time::interval(node.config.replicate_interval);
+            interval.tick().await;
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = shutdown.changed() => break,
+                }
+
+                let records: Vec<_> = {
+                    let now = SystemTime::now();
+                    let router = node.router.lock().await;
+                    let store = node.store.lock().await;
+                    store
+                        .records()
+                        .into_iter()
+                        .filter(|(key, record)| {
+                            let refreshed = now
+                                .duration_since(record.timestamp)
+                                .is_ok_and(|age| age < node.config.replicate_interval);
+                            !record.cached && !refreshed && router.closer(key) < KBUCKET_MAX_LENGTH
+                        })
+                        .collect()
+                };
+                for (key, record) in records {
+                    let published = Published {
+                        ttl: Some(record.ttl(SystemTime::now())),
+                        value: record.value,
+                        namespace: record.namespace,
+                    };
+                    node.publish(key, published).await;
+                }
+            }
+        })
+    }
+
+    /// Start the service to announce provided keys every [`NodeConfig::provider_republish_interval`]
+    pub fn announcer(&self) -> JoinHandle<()> {
+        let node = self.clone();
+        let mut shutdown = self.shutdown.subscribe();
+        tokio::spawn(async move {
+            let mut interval = time::interval(node.config.provider_republish_interval);
+            interval.tick().await;
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = shutdown.changed() => break,
+                }
+
+                let providing: Vec<Id> = {
+                    let providing = node.providing.lock().await;
+                    providing.iter().cloned().collect()
+                };
+                for key in providing {
+                    node.announce(&key).await;
+                }
+            }
+        })
+    }
+
+    /// Start the service to ping unverified contacts every [`VERIFY_INTERVAL`]. Those that answer from the
+    /// address they were given with are added to the [`RoutingTable`] by [`Node::process_response`]
+    pub fn verifier(&self) -> JoinHandle<()> {
+        let node = self.clone();
+        let mut shutdown = self.shutdown.subscribe();
+        tokio::spawn(async move {
+            let mut interval = time::interval(VERIFY_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = shutdown.changed() => break,
+                }
+
+                let contacts: Vec<NodeInfo> = {
+                    let mut unverified = node.unverified.lock().await;
+                    unverified.drain(..).collect()
+                };
+                let handles: Vec<_> = contacts
+                    .into_iter()
+                    .map(|contact| {
+                        let mut node = node.clone();
+                        tokio::spawn(async move { node.send(RequestPayload::Ping, &contact).await })
+                    })
+                    .collect();
+
+                for handle in handles {
+                    let _ = handle.await;
+                }
+            }
+        })
+    }
+
+    /// Hold contacts learned second-hand until the verifier has pinged them. Contacts the [`RoutingTable`] would
+    /// refuse anyway are never pinged, and a single [`crate::diversity::Subnet`] holds no more places than it
+    /// could take in the table, so flooding us with contacts only pushes out the oldest
+    async fn learn(&self, contacts: &[NodeInfo]) {
+        let router = self.router.lock().await;
+        let mut unverified = self.unverified.lock().await;
+        let diversity = &self.config.diversity;
+        for n in contacts {
+            if n.id == self.node_info.id
+                || router.find(&n.id).is_some()
+                || !router.admits(n)
+                || unverified.iter().any(|u| u.id == n.id)
+            {
+                continue;
+            }
+            if let Ok(Some(subnet)) = diversity.subnet(&n.address) {
+                let queued = unverified
+                    .iter()
+                    .filter(|u| diversity.subnet(&u.address) == Ok(Some(subnet)))
+                    .count();
+                if queued >= diversity.per_table {
+                    continue;
+                }
+            }
+
+            if unverified.len() >= MAX_UNVERIFIED {
+                unverified.pop_front();
+            }
+            unverified.push_back(n.clone());
+        }
+    }
+
+    /// Run `f` on the [`Store`] from a blocking thread, as a durable [`FileBackend`] writes and syncs its log
+    async fn write_store<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut Store<Id, Vec<u8>>) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let store = Arc::clone(&self.store);
+        tokio::task::spawn_blocking(move || f(&mut store.blocking_lock()))
+            .await
+            .expect("store task panicked")
+    }
+
+    /// Write a [`Snapshot`] of the [`RoutingTable`] to [`NodeConfig::snapshot_path`]
+    pub async fn save_snapshot(&self) -> io::Result<()> {
+        let path = match self.config.snapshot_path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+
+        let snapshot = {
+            let router = self.router.lock().await;
+            Snapshot::new(&router)
+        };
+        let buffer = serde_json::to_vec(&snapshot)?;
+
+        // Write then rename so a crash mid-write never leaves a truncated snapshot behind
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, buffer).await?;
+        fs::rename(&tmp, path).await
+    }
+
+    /// Generate key pairs until one's [`Id`] solves the static puzzle of `difficulty`, then solve its dynamic
+    /// puzzle
+    fn generate_identity(difficulty: Difficulty) -> (SigningKey, Id) {
+        loop {
+            let signing_key = SigningKey::generate(&mut OsRng);
+            let id = Id::from_key(&signing_key.verifying_key().to_bytes());
+            if id.static_puzzle() >= difficulty.static_bits {
+                return (signing_key, id.solve(difficulty.dynamic_bits));
+            }
+        }
+    }
+
+    /// Read the node's secret key and the solution of its dynamic puzzle from disk, generating and writing new
+    /// ones if there are none yet. A key whose [`Id`] does not solve the static puzzle of `difficulty` is refused,
+    /// a missing or too easy dynamic puzzle is solved again and written back
+    async fn load_identity(path: &PathBuf, difficulty: Difficulty) -> io::Result<(SigningKey, Id)> {
+        match fs::read(path).await {
+            Ok(buffer) => {
+                if buffer.len() != SECRET_KEY_LENGTH && buffer.len() != SECRET_KEY_LENGTH + ID_SIZE
+                {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "identity is not an ed25519 key",
+                    ));
+                }
+                let (secret, puzzle) = buffer.split_at(SECRET_KEY_LENGTH);
+                let signing_key =
+                    SigningKey::from_bytes(secret.try_into().expect("checked key length"));
+                let id = Id::from_key(&signing_key.verifying_key().to_bytes());
+                if id.static_puzzle() < difficulty.static_bits {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "identity does not meet the static puzzle difficulty",
+                    ));
+                }
+
+                match <[u8; ID_SIZE]>::try_from(puzzle).map(Id::new) {
+                    Ok(puzzle) if id.dynamic_puzzle(&puzzle) >= difficulty.dynamic_bits => {
+                        Ok((signing_key, puzzle))
+                    }
+                    _ => {
+                        let puzzle = id.solve(difficulty.dynamic_bits);
+                        Self::write_identity(path, &signing_key, &puzzle).await?;
+                        Ok((signing_key, puzzle))
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let (signing_key, puzzle) = Self::generate_identity(difficulty);
+                Self::write_identity(path, &signing_key, &puzzle).await?;
+                Ok((signing_key, puzzle))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Write the node's secret key followed by its puzzle solution so only its owner can read them. They are
+    /// synced to a temporary file first and renamed into place, so a crash never leaves a torn key behind
+    async fn write_identity(
+        path: &PathBuf,
+        signing_key: &SigningKey,
+        puzzle: &Id,
+    ) -> io::Result<()> {
+        let tmp = path.with_extension("tmp");
+        // A leftover file could have looser permissions, which opening it would keep
+        match fs::remove_file(&tmp).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut file = options.open(&tmp).await?;
+        file.write_all(&signing_key.to_bytes()).await?;
+        file.write_all(puzzle.as_bytes()).await?;
+        file.sync_all().await?;
+        fs::rename(&tmp, path).await
+    }
+
+    /// Read a [`Snapshot`] from disk, `None` if there is no snapshot yet
+    async fn load_snapshot(path: &PathBuf) -> io::Result<Option<Snapshot>> {
+        match fs::read(path).await {
+            Ok(buffer) => Ok(Some(serde_json::from_slice(&buffer)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Find the k closest nodes to `id` in the network by iteratively querying closer and closer nodes
+    pub async fn lookup(&self, id: &Id) -> Vec<NodeInfo> {
+        self.iterate(id, RequestPayload::FindNode { id: id.clone() }, 1)
+            .await
+            .closest
+    }
+
+    /// Find a value in the network, caching it on the closest node along the lookup path that did not have it.
+    /// When disjoint paths find different values only the one most of them found is returned
+    pub async fn get(&self, key: &Id) -> Option<Vec<u8>> {
+        {
+            let store = self.store.lock().await;
+            if let Some((value, _)) = store.get(key) {
+                return Some(value);
+            }
+        }
+
+        let request = RequestPayload::FindValue { key: key.clone() };
+        let lookup = self.iterate(key, request, 1).await;
+        let (value, namespace) = lookup.agreed?;
+        self.cache(key, &value, namespace, &lookup.closest);
+
+        Some(value)
+    }
+
+    /// Find a value in a namespace, collecting up to [`RECORD_QUORUM`] values and returning the one the
+    /// namespace's validator selects. `None` when no value is valid or there is no validator for the namespace
+    pub async fn get_record(&self, namespace: &str, key: &Id) -> Option<Vec<u8>> {
+        let validator = self.config.validators.get(namespace)?;
+
+        let mut values = Vec::new();
+        {
+            let store = self.store.lock().await;
+            if let Some(record) = store.record(key) {
+                values.push(record.value);
+            }
+        }
+
+        let request = RequestPayload::FindValue { key: key.clone() };
+        let lookup = self.iterate(key, request, RECORD_QUORUM).await;
+        values.extend(lookup.values.into_iter().map(|(value, _)| value));
+        values.retain(|value| validator.validate(key, value).is_ok());
+        if values.is_empty() {
+            return None;
+        }
+
+        let value = values.swap_remove(validator.select(key, &values));
+        self.cache(key, &value, Some(namespace.to_string()), &lookup.closest);
+        Some(value)
+    }
+
+    /// Store a found value on the closest node along the lookup path that did not return it
+    fn cache(&self, key: &Id, value: &[u8], namespace: Option<String>, path: &[NodeInfo]) {
+        if let Some(closest) = path.first() {
+            let mut node = self.clone();
+            let closest = closest.clone();
+            // The receiver scales the ttl down further by how far it is from the key
+            let request = RequestPayload::Store {
+                key: key.clone(),
+                value: value.to_vec(),
+                ttl: Some(self.config.cache_ttl),
+                namespace,
+                cached: true,
+            };
+            tokio::spawn(async move { node.send(request, &closest).await });
+        }
+    }
+
+    /// Iteratively send `request` to the closest nodes to `id` until the k closest have all responded or
+    /// `quorum` values or providers are found. With [`NodeConfig::disjoint_paths`] above one the known closest
+    /// nodes are dealt between that many lookups run in parallel, which never query the same node, so a single
+    /// malicious node can only steer one of them
+    async fn iterate(&self, id: &Id, request: RequestPayload, quorum: usize) -> Lookup {
+        let closest = {
+            let router = self.router.lock().await;
+            router.closest(id, KBUCKET_MAX_LENGTH)
+        };
+        let paths = cmp::max(1, cmp::min(self.config.disjoint_paths, closest.len()));
+        let mut shortlists = vec![Vec::new(); paths];
+        for (i, n) in closest.into_iter().enumerate() {
+            shortlists[i % paths].push(n);
+        }
+
+        let queried = Arc::new(Mutex::new(HashSet::from([self.node_info.id.clone()])));
+        let handles: Vec<_> = shortlists
+            .into_iter()
+            .map(|shortlist| {
+                let node = self.clone();
+                let (id, request, queried) = (id.clone(), request.clone(), Arc::clone(&queried));
+                tokio::spawn(
+                    async move { node.path(&id, request, quorum, shortlist, queried).await },
+                )
+            })
+            .collect();
+
+        let mut lookups = Vec::new();
+        for handle in handles {
+            if let Ok(lookup) = handle.await {
+                lookups.push(lookup);
+            }
+        }
+        Lookup::merge(id, lookups)
+    }
+
+    /// A single lookup path of [`Node::iterate`] starting from `shortlist`. Nodes are only queried if no path has
+    /// queried them yet
+    async fn path(
+        &self,
+        id: &Id,
+        request: RequestPayload,
+        quorum: usize,
+        mut shortlist: Vec<NodeInfo>,
+        queried: Arc<Mutex<HashSet<Id>>>,
+    ) -> Lookup {
+        let mut responded = HashSet::new();
+        let mut values = Vec::new();
+        let mut providers: Vec<NodeInfo> = Vec::new();
+
+        while values.len() + providers.len() < quorum {
+            let candidates: Vec<NodeInfo> = {
+                let mut queried = queried.lock().await;
+                shortlist
+                    .iter()
+                    .filter(|n| queried.insert(n.id.clone()))
+                    .take(ALPHA)
+                    .cloned()
+                    .collect()
+            };
+            if candidates.is_empty() {
+                break;
+            }
+
+            let handles: Vec<_> = candidates
+                .into_iter()
+                .map(|candidate| {
+                    let mut node = self.clone();
+                    let request = request.clone();
+                    tokio::spawn(async move {
+                        let response = node.send(request, &candidate).await;
+                        (candidate, response)
+                    })
+                })
+                .collect();
+
+            for handle in handles {
+                let (candidate, response) = match handle.await {
+                    Ok(x) => x,
+                    Err(_) => continue,
+                };
+
+                let (closest, found) = match response {
+                    Some(ResponsePayload::FindNode { closest }) => (closest, Vec::new()),
+                    Some(ResponsePayload::Providers {
+                        providers: found,
+                        closest,
+                    }) => (closest, found),
+                    Some(ResponsePayload::Value { value, namespace }) => {
+                        values.push((value, namespace));
+                        continue;
+                    }
+                    _ => {
+                        shortlist.retain(|n| n.id != candidate.id);
+                        continue;
+                    }
+                };
+
+                // Providers are handed back to the caller to contact, so they face the same checks as contacts
+                let admissible =
+                    |n: &NodeInfo| n.verify() && self.config.difficulty.check(&n.id, &n.puzzle);
+                if !closest.iter().chain(&found).all(admissible) {
+                    self.penalise(&candidate, Offence::BadContacts).await;
+                }
+                let found: Vec<NodeInfo> = found.into_iter().filter(admissible).collect();
+                for n in self.admitted(found) {
+                    if !providers.iter().any(|p| p.id == n.id) {
+                        providers.push(n);
+                    }
+                }
+
+                responded.insert(candidate.id);
+                let closest: Vec<NodeInfo> = closest.into_iter().filter(admissible).collect();
+                self.learn(&closest).await;
+                for n in closest {
+                    if n.id != self.node_info.id && !shortlist.iter().any(|s| s.id == n.id) {
+                        shortlist.push(n);
+                    }
+                }
+            }
+
+            shortlist.sort_by_key(|n| n.id.xor(id));
+            shortlist.truncate(KBUCKET_MAX_LENGTH);
+        }
+
+        shortlist.retain(|n| responded.contains(&n.id));
+        Lookup {
+            closest: shortlist,
+            values,
+            providers,
+            ..Default::default()
+        }
+    }
+
+    /// Providers that are neither banned nor at an address the [`DiversityLimits`] could not be applied to,
+    /// as [`RoutingTable::admits`] requires of contacts
+    fn admitted(&self, providers: Vec<NodeInfo>) -> Vec<NodeInfo> {
+        let reputation = self.reputation.lock().expect("reputation lock poisoned");
+        providers
+            .into_iter()
+            .filter(|n| {
+                !reputation.is_banned_node(&n.id, &n.address)
+                    && self.config.diversity.subnet(&n.address).is_ok()
+            })
+            .collect()
+    }
+
+    /// Announce this node as a provider of `key` to the k closest nodes, it is announced again every
+    /// [`NodeConfig::provider_republish_interval`] until [`Node::unprovide`] is called. Returns the number of
+    /// nodes that accepted the announcement
+    pub async fn provide(&self, key: Id) -> usize {
+        {
+            let mut providing = self.providing.lock().await;
+            providing.insert(key.clone());
+        }
+        self.announce(&key).await
+    }
+
+    /// Stop announcing this node as a provider of `key`, other nodes forget it after their provider ttl
+    pub async fn unprovide(&self, key: &Id) {
+        let mut providing = self.providing.lock().await;
+        providing.remove(key);
+    }
+
+    /// Find the nodes that provide `key`
+    pub async fn get_providers(&self, key: &Id) -> Vec<NodeInfo> {
+        let mut providers = {
+            let providers = self.providers.lock().await;
+            providers.get(key)
+        };
+
+        let request = RequestPayload::GetProviders { key: key.clone() };
+        let lookup = self
+            .iterate(key, request, MAX_PROVIDERS.saturating_sub(providers.len()))
+            .await;
+        for n in lookup.providers {
+            if !providers.iter().any(|p| p.id == n.id) {
+                providers.push(n);
+            }
+        }
+        providers
+    }
+
+    /// Send a provider announcement for `key` to the k closest nodes
+    async fn announce(&self, key: &Id) -> usize {
+        let closest = self.lookup(key).await;
+        let handles: Vec<_> = closest
+            .into_iter()
+            .map(|n| {
+                let mut node = self.clone();
+                let request = RequestPayload::AddProvider { key: key.clone() };
+                tokio::spawn(async move {
+                    matches!(node.send(request, &n).await, Some(ResponsePayload::Stored))
+                })
+            })
+            .collect();
+
+        let mut stored = 0;
+        for handle in handles {
+            if let Ok(true) = handle.await {
+                stored += 1;
+            }
+        }
+        stored
+    }
+
+    /// Publish a record to the network as its original publisher, it is republished every
+    /// [`NodeConfig::republish_interval`] until [`Node::unpublish`] is called. Returns the number of nodes that stored it
+    pub async fn put(&self, key: Id, value: Vec<u8>, ttl: Option<Duration>) -> usize {
+        let published = Published {
+            value,
+            ttl,
+            namespace: None,
+        };
+        self.own(key, published).await
+    }
+
+    /// [`Node::put`] a value in a namespace, checking it with the namespace's validator first
+    pub async fn put_record(
+        &self,
+        namespace: &str,
+        key: Id,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> Result<usize, Rejection> {
+        let validator =
+            self.config
+                .validators
+                .get(namespace)
+                .ok_or_else(|| Rejection::Invalid {
+                    reason: format!("no validator for namespace {}", namespace),
+                })?;
+        validator
+            .validate(&key, &value)
+            .map_err(|reason| Rejection::Invalid { reason })?;
+
+        let published = Published {
+            value,
+            ttl,
+            namespace: Some(namespace.to_string()),
+        };
+        Ok(self.own(key, published).await)
+    }
+
+    /// Sign and [`Node::put_record`] a [`MutableRecord`] under the key derived from `signing_key` and `salt`.
+    /// Nodes holding an equal or higher `seq` refuse it
+    pub async fn put_mutable(
+        &self,
+        signing_key: &SigningKey,
+        salt: Vec<u8>,
+        seq: u64,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> Result<usize, Rejection> {
+        let record = MutableRecord::new(signing_key, salt, seq, value);
+        let key = MutableRecord::key(&record.public_key, &record.salt);
+        self.put_record(MUTABLE_NAMESPACE, key, record.encode(), ttl)
+            .await
+    }
+
+    /// Find the [`MutableRecord`] with the highest `seq` for a public key and salt
+    pub async fn get_mutable(
+        &self,
+        public_key: &[u8; PUBLIC_KEY_LENGTH],
+        salt: &[u8],
+    ) -> Option<MutableRecord> {
+        let key = MutableRecord::key(public_key, salt);
+        let value = self.get_record(MUTABLE_NAMESPACE, &key).await?;
+        MutableRecord::decode(&value).ok()
+    }
+
+    /// [`Node::put_record`] an immutable value under the hash of its contents, returning that key along
+    /// with the number of nodes that stored it
+    pub async fn put_immutable(
+        &self,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> Result<(Id, usize), Rejection> {
+        let key = ImmutableValidator::key(&value);
+        let stored = self
+            .put_record(IMMUTABLE_NAMESPACE, key.clone(), value, ttl)
+            .await?;
+        Ok((key, stored))
+    }
+
+    /// Find an immutable value, only values that hash to `key` are returned
+    pub async fn get_immutable(&self, key: &Id) -> Option<Vec<u8>> {
+        self.get_record(IMMUTABLE_NAMESPACE, key).await
+    }
+
+    /// Track a record as published by this node and publish it
+    async fn own(&self, key: Id, published: Published) -> usize {
+        {
+            let mut owned = self.published.lock().await;
+            owned.insert(key.clone(), published.clone());
+        }
+        self.publish(key, published).await
+    }
+
+    /// [`Node::put`] a value serialized with serde
+    pub async fn put_typed<T: Serialize>(
+        &self,
+        key: Id,
+        value: &T,
+        ttl: Option<Duration>,
+    ) -> serde_json::Result<usize> {
+        let value = serde_json::to_vec(value)?;
+        Ok(self.put(key, value, ttl).await)
+    }
+
+    /// [`Node::get`] a value and deserialize it with serde, `None` if it is missing or not a `T`
+    pub async fn get_typed<T: DeserializeOwned>(&self, key: &Id) -> Option<T> {
+        let value = self.get(key).await?;
+        serde_json::from_slice(&value).ok()
+    }
+
+    /// Stop republishing a record, it will expire from the network after its ttl
+    pub async fn unpublish(&self, key: &Id) {
+        let mut published = self.published.lock().await;
+        published.remove(key);
+    }
+
+    /// Store a record on the k closest nodes to its key, returning the number of nodes that stored it. It is
+    /// stored locally too, so we can serve it and hand it over ourselves
+    async fn publish(&self, key: Id, published: Published) -> usize {
+        let ttl = published
+            .ttl
+            .unwrap_or(self.config.default_record_ttl)
+            .min(self.config.max_record_ttl);
+        let record = Record {
+            namespace: published.namespace.clone(),
+            ..Record::new(published.value.clone(), ttl)
+        };
+        let local = key.clone();
+        if let Err(e) = self
+            .write_store(move |store| store.upsert(local, record))
+            .await
+        {
+            eprintln!("failed to store published record: {}", e);
+        }
+
+        let closest = self.lookup(&key).await;
+        let handles: Vec<_> = closest
+            .into_iter()
+            .map(|n| {
+                let mut node = self.clone();
+                let request = RequestPayload::Store {
+                    key: key.clone(),
+                    value: published.value.clone(),
+                    ttl: published.ttl,
+                    namespace: published.namespace.clone(),
+                    cached: false,
+                };
+                tokio::spawn(async move {
+                    matches!(node.send(request, &n).await, Some(ResponsePayload::Stored))
+                })
+            })
+            .collect();
+
+        let mut stored = 0;
+        for handle in handles {
+            if let Ok(true) = handle.await {
+                stored += 1;
+            }
+        }
+        stored
+    }
+
+    /// Upsert a node we heard from into the [`RoutingTable`], returning `true` if it is new
+    async fn observe(&self, node_info: NodeInfo) -> bool {
+        let mut router = self.router.lock().await;
+        let is_new = router.find(&node_info.id).is_none();
+        router.upsert(node_info) && is_new
+    }
+
+    /// Push every stored record that `node_info` is closer to than we are to it, among the keys we are one of
+    /// the k closest nodes to
+    fn hand_over(&self, node_info: NodeInfo) -> JoinHandle<()> {
+        let mut node = self.clone();
+        tokio::spawn(async move {
+            let records = {
+                let store = node.store.lock().await;
+                store.records()
+            };
+            let records: Vec<_> = {
+                let router = node.router.lock().await;
+                records
+                    .into_iter()
+                    .filter(|(key, record)| {
+                        !record.cached
+                            && node_info.id.xor(key) < node.node_info.id.xor(key)
+                            && router.closer(key) < KBUCKET_MAX_LENGTH
+                    })
+                    .collect()
+            };
+
+            for (key, record) in records {
+                let ttl = record.ttl(SystemTime::now());
+                let request = RequestPayload::Store {
+                    key,
+                    value: record.value,
+                    ttl: Some(ttl),
+                    namespace: record.namespace,
+                    cached: false,
+                };
+                node.send(request, &node_info).await;
+            }
+        })
+    }
+
+    /// Handle a request and send a response. The address the request came from replaces the one the requester
+    /// reported, so responses can't be aimed at a third party. As that address could still be spoofed the
+    /// requester is only [`Node::learn`]ed, it joins the [`RoutingTable`] once it answers the verifier
+    async fn process_request(&mut self, mut message: RequestHandle, address: SocketAddr) {
         println!("processing request");
+        message.source.address = address.to_string();
         match message.request {
             RequestPayload::Ping => {
-                {
-                    let mut router = self.router.lock().await;
-                    router.upsert(message.source.clone());
-                }
+                self.learn(slice::from_ref(&message.source)).await;
                 let response = Message::Response(ResponseHandle {
                     id: Id::random(),
-                    source: message.source.clone(),
+                    source: self.advertised().await,
                     request_id: message.id,
+                    observed: Some(address),
                     response: ResponsePayload::Pong,
                 });
 
-                self.rpc.send(&response, &message.source).await;
+                self.rpc.send(&response, &message.source, address).await;
             }
-            RequestPayload::Store { key, value } => {
+            RequestPayload::Store {
+                key,
+                value,
+                ttl,
+                namespace,
+                cached,
+            } => {
+                let ttl = ttl
+                    .unwrap_or(self.config.default_record_ttl)
+                    .min(self.config.max_record_ttl);
+                self.learn(slice::from_ref(&message.source)).await;
+                let validated = namespace
+                    .as_ref()
+                    .is_some_and(|namespace| self.config.validators.get(namespace).is_some());
+                let ttl = {
+                    let router = self.router.lock().await;
+                    // Records cached far from their key age out faster to stop over-caching
+                    scaled_ttl(ttl, router.closer(&key))
+                };
+                let source = message.source.id.clone();
+                let limits = self.config.store_limits.clone();
+                let validator = namespace
+                    .as_ref()
+                    .map(|namespace| (namespace.clone(), self.config.validators.get(namespace)));
+                let response = self
+                    .write_store(move |store| {
+                        let admitted = store
+                            .admit(&key, &value, address.ip(), &limits)
+                            .and_then(|()| store.bind(&key, namespace.as_deref()))
+                            .and_then(|()| match validator {
+                                Some((_, Some(validator))) => {
+                                    store.check(&key, &value, validator.as_ref())
+                                }
+                                Some((namespace, None)) => Err(Rejection::Invalid {
+                                    reason: format!("no validator for namespace {}", namespace),
+                                }),
+                                None => Ok(()),
+                            });
+
+                        match admitted {
+                            Ok(()) => {
+                                let record = Record {
+                                    source: Some(source),
+                                    origin: Some(address.ip()),
+                                    namespace,
+                                    cached,
+                                    ..Record::new(value, ttl)
+                                };
+                                if let Err(e) = store.upsert_replica(key, record) {
+                                    eprintln!("failed to store record: {}", e);
+                                }
+                                ResponsePayload::Stored
+                            }
+                            Err(reason) => {
+                                eprintln!("rejected record from {:?}: {}", source, reason);
+                                ResponsePayload::Rejected { reason }
+                            }
+                        }
+                    })
+                    .await;
+                // Only records a validator refused are misbehaviour, the rest are honest limits and races
+                if let ResponsePayload::Rejected {
+                    reason: Rejection::Invalid { .. },
+                } = response
                 {
-                    let mut router = self.router.lock().await;
-                    router.upsert(message.source.clone());
-                    let mut store = self.store.lock().await;
-                    store.upsert(key, value);
+                    if validated {
+                        self.penalise(&message.source, Offence::InvalidRecord).await;
+                    }
                 }
                 let response = Message::Response(ResponseHandle {
                     id: Id::random(),
-                    source: message.source.clone(),
+                    source: self.advertised().await,
                     request_id: message.id,
-                    response: ResponsePayload::Pong,
+                    observed: Some(address),
+                    response,
+                });
+                self.rpc.send(&response, &message.source, address).await;
+            }
+            RequestPayload::FindValue { key } => {
+                self.learn(slice::from_ref(&message.source)).await;
+                let record = {
+                    let store = self.store.lock().await;
+                    store.record(&key)
+                };
+                let response = match record {
+                    Some(record) => ResponsePayload::Value {
+                        value: record.value,
+                        namespace: record.namespace,
+                    },
+                    None => {
+                        let router = self.router.lock().await;
+                        let closest = router.closest(&key, KBUCKET_MAX_LENGTH);
+                        ResponsePayload::FindNode { closest }
+                    }
+                };
+
+                let response = Message::Response(ResponseHandle {
+                    id: Id::random(),
+                    source: self.advertised().await,
+                    request_id: message.id,
+                    observed: Some(address),
+                    response,
+                });
+
+                self.rpc.send(&response, &message.source, address).await;
+            }
+            RequestPayload::AddProvider { key } => {
+                self.learn(slice::from_ref(&message.source)).await;
+                // Limited by the address it was announced from, which unlike its claimed address cannot be chosen
+                let subnet = self
+                    .config
+                    .diversity
+                    .subnet(&address.to_string())
+                    .unwrap_or(None);
+                let added = {
+                    let mut providers = self.providers.lock().await;
+                    providers.add(
+                        key,
+                        message.source.clone(),
+                        address.ip(),
+                        subnet,
+                        self.config.provider_ttl,
+                    )
+                };
+                let response = match added {
+                    Ok(()) => ResponsePayload::Stored,
+                    Err(reason) => {
+                        eprintln!("rejected provider {:?}: {}", message.source.id, reason);
+                        ResponsePayload::Rejected { reason }
+                    }
+                };
+
+                let response = Message::Response(ResponseHandle {
+                    id: Id::random(),
+                    source: self.advertised().await,
+                    request_id: message.id,
+                    observed: Some(address),
+                    response,
+                });
+
+                self.rpc.send(&response, &message.source, address).await;
+            }
+            RequestPayload::GetProviders { key } => {
+                self.learn(slice::from_ref(&message.source)).await;
+                let providers = {
+                    let providers = self.providers.lock().await;
+                    providers.get(&key)
+                };
+                let closest = {
+                    let router = self.router.lock().await;
+                    router.closest(&key, KBUCKET_MAX_LENGTH)
+                };
+
+                let response = Message::Response(ResponseHandle {
+                    id: Id::random(),
+                    source: self.advertised().await,
+                    request_id: message.id,
+                    observed: Some(address),
+                    response: ResponsePayload::Providers { providers, closest },
                 });
-                self.rpc.send(&response, &message.source).await;
+
+                self.rpc.send(&response, &message.source, address).await;
             }
             RequestPayload::FindNode { id } => {
-                let closest: Vec<NodeInfo>;
-                {
-                    let mut router = self.router.lock().await;
-                    router.upsert(message.source.clone());
-                    closest = router.closest(&id, KBUCKET_MAX_LENGTH);
-                }
+                self.learn(slice::from_ref(&message.source)).await;
+                let closest = {
+                    let router = self.router.lock().await;
+                    router.closest(&id, KBUCKET_MAX_LENGTH)
+                };
 
                 let response = Message::Response(ResponseHandle {
                     id: Id::random(),
-                    source: message.source.clone(),
+                    source: self.advertised().await,
                     request_id: message.id,
+                    observed: Some(address),
                     response: ResponsePayload::FindNode { closest },
                 });
 
-                self.rpc.send(&response, &message.source).await;
+                self.rpc.send(&response, &message.source, address).await;
             }
         }
     }
 
-    /// Handle a request reponse, looking up pending requests, notify requestee
-    async fn process_response(&mut self, message: ResponseHandle) {
-        let mut pending = self.pending.lock().await;
+    /// Penalise a node for an [`Offence`], dropping it from the [`RoutingTable`] if this gets it banned
+    async fn penalise(&self, node_info: &NodeInfo, offence: Offence) {
+        let banned = {
+            let mut reputation = self.reputation.lock().expect("reputation lock poisoned");
+            reputation.penalise(Peer::Id(node_info.id.clone()), offence)
+        };
+        if banned {
+            eprintln!("banned {:?} for {:?}", node_info.id, offence);
+            self.evict(&Peer::Id(node_info.id.clone())).await;
+        }
+    }
+
+    /// Ban a peer by [`Id`] or address for `duration`, or [`NodeConfig::ban_duration`] when `None`
+    pub async fn ban(&self, peer: Peer, duration: Option<Duration>) {
         {
-            let mut router = self.router.lock().await;
-            router.upsert(message.source);
+            let mut reputation = self.reputation.lock().expect("reputation lock poisoned");
+            reputation.ban(peer.clone(), duration.unwrap_or(self.config.ban_duration));
         }
+        self.evict(&peer).await;
+    }
+
+    /// Lift a ban on a peer
+    pub fn unban(&self, peer: &Peer) {
+        let mut reputation = self.reputation.lock().expect("reputation lock poisoned");
+        reputation.unban(peer);
+    }
 
-        match pending.remove(&message.request_id) {
-            Some(tx) => {
-                println!("sending response back to send fn {:?}", message.id);
-                tx.send(message.response)
-                    .expect("failed to send response via oneshot");
+    /// Remove every contact matching a banned peer from the [`RoutingTable`]
+    async fn evict(&self, peer: &Peer) {
+        let mut router = self.router.lock().await;
+        let matching: Vec<NodeInfo> = router
+            .nodes()
+            .into_iter()
+            .filter(|n| match peer {
+                Peer::Id(id) => &n.id == id,
+                Peer::Address(_) => n
+                    .address
+                    .parse::<SocketAddr>()
+                    .is_ok_and(|address| Peer::address(address.ip()) == *peer),
+            })
+            .collect();
+        for n in matching {
+            router.remove(&n);
+        }
+    }
+
+    /// Handle a request reponse, looking up pending requests, notify requestee. Responses from a node or address
+    /// other than the one the request went to, or of the wrong kind, are discarded without touching the
+    /// [`RoutingTable`], and the node is penalised for a wrong kind
+    async fn process_response(&mut self, mut message: ResponseHandle, address: SocketAddr) {
+        let pending = {
+            let mut pending = self.pending.lock().await;
+            let (expected, malformed) = match pending.get(&message.request_id) {
+                Some(p) => {
+                    let answered = p.destination == message.source.id && p.address == address;
+                    let expects = p.kind.expects(&message.response);
+                    (answered && expects, answered && !expects)
+                }
+                None => {
+                    eprintln!("Received response for request that has not been tracked");
+                    return;
+                }
+            };
+            // Only the node the request went to can sign a response to it, so a wrong kind is its own doing
+            if malformed {
+                drop(pending);
+                self.penalise(&message.source, Offence::Malformed).await;
+                return;
             }
-            None => {
-                eprintln!("Received response for request that has not been tracked")
+            if !expected {
+                eprintln!(
+                    "Discarding unexpected response from {:?} at {}",
+                    message.source.id, address
+                );
+                return;
             }
+            pending
+                .remove(&message.request_id)
+                .expect("pending request was just found")
+        };
+
+        // The response came from the address the request went to, which is the one to remember
+        message.source.address = address.to_string();
+        if let Some(observed) = message.observed {
+            self.vote(&message.source, observed).await;
+        }
+        // Records are only handed over once the node has answered us from its address, so a request with a
+        // spoofed source cannot aim them at someone else
+        if self.observe(message.source.clone()).await {
+            self.hand_over(message.source);
+        }
+        println!("sending response back to send fn {:?}", message.id);
+        if pending.tx.send(message.response).is_err() {
+            eprintln!("Received response for request that has already given up")
+        }
+    }
+
+    /// Count the address a peer observed us at towards our external address. Only contacts already verified
+    /// into the [`RoutingTable`] at the address they answered from vote, and only once per
+    /// [`crate::diversity::Subnet`]. Peers in allowlisted networks see us at a local address and do not vote
+    async fn vote(&self, voter: &NodeInfo, observed: SocketAddr) {
+        let verified = {
+            let router = self.router.lock().await;
+            router.find(&voter.id).map(|n| n.address) == Some(voter.address.clone())
+        };
+        let subnet = match self.config.diversity.subnet(&voter.address) {
+            Ok(Some(subnet)) if verified => subnet,
+            _ => return,
+        };
+
+        if let Some(learned) = self.external.lock().await.vote(subnet, observed) {
+            eprintln!("learned external address {}", learned);
         }
     }
 
-    /// Send a request and wait and return a response.
+    /// Our [`NodeInfo`] as sent to other nodes, with the external address once enough peers agree on it. The
+    /// bind address is kept until then, which is unusable when bound to `0.0.0.0` or behind NAT
+    pub async fn advertised(&self) -> NodeInfo {
+        let mut node_info = self.node_info.clone();
+        if let Some(learned) = self.external.lock().await.learned() {
+            node_info.address = learned.to_string();
+        }
+        node_info
+    }
+
+    /// Send a request and wait and return a response, `None` if the request timed out
     pub async fn send(
         &mut self,
         request: RequestPayload,
         destination: &NodeInfo,
     ) -> Option<ResponsePayload> {
+        let address = match rpc::resolve(&destination.address).await {
+            Some(address) => address,
+            None => {
+                eprintln!("failed to resolve {}", destination.address);
+                return None;
+            }
+        };
         let request_id = Id::random();
+        let kind = request.kind();
         let message = Message::Request(RequestHandle {
             id: request_id.clone(),
-            source: self.node_info.to_owned(),
-            request: request,
+            source: self.advertised().await,
+            request,
         });
-        // TODO: Change this to a oneshot channel
         let (tx, rx) = oneshot::channel();
         {
             let mut pending = self.pending.lock().await;
-            pending.insert(request_id, tx);
+            pending.insert(
+                request_id.clone(),
+                Pending {
+                    destination: destination.id.clone(),
+                    address,
+                    kind,
+                    tx,
+                },
+            );
+        }
+        self.rpc.send(&message, destination, address).await;
+        match timeout(RESPONSE_TIMEOUT, rx).await {
+            Ok(response) => response.ok(),
+            Err(_) => {
+                {
+                    let mut pending = self.pending.lock().await;
+                    pending.remove(&request_id);
+                }
+                {
+                    let mut router = self.router.lock().await;
+                    router.failed(&destination.id);
+                }
+                // The node may have restarted and lost our session
+                self.rpc.forget(&address).await;
+                None
+            }
         }
-        self.rpc.send(&message, destination).await;
-        let response = timeout(RESPONSE_TIMEOUT, rx)
-            .await
-            .expect("waiting for request response timed out");
-        Some(response.expect("failed to receive response via oneshot"))
     }
 }
 
@@ -206,3 +1575,525 @@ impl Display for Node {
         f.write_str(&self.node_info.id.hex())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        external::MIN_VOTES,
+        rpc::{Chunk, MESSAGE_SIZE},
+        session::Frame,
+    };
+
+    /// A [`NodeInfo`] for a peer at `address` with a fresh key pair
+    fn peer(address: SocketAddr) -> NodeInfo {
+        NodeInfo::new(
+            &SigningKey::generate(&mut OsRng).verifying_key(),
+            address.to_string(),
+        )
+    }
+
+    fn request(source: &NodeInfo, request: RequestPayload) -> RequestHandle {
+        RequestHandle {
+            id: Id::random(),
+            source: source.clone(),
+            request,
+        }
+    }
+
+    #[test]
+    fn adversarial_path() {
+        let target = Id::new([0; ID_SIZE]);
+        let honest = |port: u16| {
+            (0..KBUCKET_MAX_LENGTH)
+                .map(|_| NodeInfo::with_id(Id::random(), format!("1.1.1.1:{}", port)))
+                .collect::<Vec<_>>()
+        };
+        // Every node the hijacked path claims is closer than any honest one
+        let adversarial: Vec<NodeInfo> = (0..KBUCKET_MAX_LENGTH as u8)
+            .map(|i| {
+                let mut id = [0; ID_SIZE];
+                id[ID_SIZE - 1] = i + 1;
+                NodeInfo::with_id(Id::new(id), "6.6.6.6:8080".to_string())
+            })
+            .collect();
+        let value = |v: u8| (vec![v], None);
+        let lookup = |closest: Vec<NodeInfo>, values: Vec<Found>| Lookup {
+            closest,
+            values,
+            ..Default::default()
+        };
+
+        let merged = Lookup::merge(
+            &target,
+            vec![
+                lookup(honest(1), vec![value(1)]),
+                lookup(adversarial.clone(), vec![value(6), value(6)]),
+                lookup(honest(2), vec![value(1)]),
+            ],
+        );
+        assert_eq!(merged.closest.len(), KBUCKET_MAX_LENGTH);
+        let hijacked = merged
+            .closest
+            .iter()
+            .filter(|n| adversarial.contains(n))
+            .count();
+        assert_eq!(hijacked, KBUCKET_MAX_LENGTH.div_ceil(3));
+        assert_eq!(merged.agreed, Some(value(1)));
+        assert_eq!(merged.values, vec![value(1), value(6)]);
+
+        // Paths that disagree with no majority agree on nothing
+        let merged = Lookup::merge(
+            &target,
+            vec![
+                lookup(honest(1), vec![value(1)]),
+                lookup(adversarial, vec![value(6)]),
+            ],
+        );
+        assert_eq!(merged.agreed, None);
+    }
+
+    #[tokio::test]
+    async fn identity() {
+        let path = std::env::temp_dir().join(format!("kademlia-{}.key", Id::random().hex()));
+        let difficulty = Difficulty {
+            static_bits: 0,
+            dynamic_bits: 8,
+        };
+        let (signing_key, puzzle) = Node::load_identity(&path, difficulty).await.unwrap();
+        let (reloaded, reloaded_puzzle) = Node::load_identity(&path, difficulty).await.unwrap();
+        assert_eq!(reloaded.to_bytes(), signing_key.to_bytes());
+        assert_eq!(reloaded_puzzle, puzzle);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).await.unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        assert!(!path.with_extension("tmp").exists());
+
+        // A key written without its puzzle has one solved and kept
+        fs::write(&path, signing_key.to_bytes()).await.unwrap();
+        let (reloaded, puzzle) = Node::load_identity(&path, difficulty).await.unwrap();
+        let id = Id::from_key(&reloaded.verifying_key().to_bytes());
+        assert!(difficulty.check(&id, &puzzle));
+        assert_eq!(
+            fs::read(&path).await.unwrap().len(),
+            SECRET_KEY_LENGTH + ID_SIZE
+        );
+        fs::remove_file(path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn spoofed_junk() {
+        let node = Node::new("127.0.0.1:19104".to_string()).await.unwrap();
+        let handles = node.start();
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:19105")
+            .await
+            .unwrap();
+        let source = socket.local_addr().unwrap();
+
+        // Anyone can send junk claiming an honest peer's address, it must not count against it
+        for _ in 0..100 {
+            socket
+                .send_to(&[0xFF; 64], "127.0.0.1:19104")
+                .await
+                .unwrap();
+        }
+        time::sleep(Duration::from_millis(200)).await;
+        {
+            let reputation = node.reputation.lock().unwrap();
+            assert!(!reputation.is_banned(&Peer::address(source.ip())));
+            assert_eq!(reputation.score(&Peer::address(source.ip())), 0);
+        }
+
+        node.shutdown().await.unwrap();
+        for handle in handles {
+            handle.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn unverified_contact() {
+        let a = Node::new("127.0.0.1:19106".to_string()).await.unwrap();
+        let b = Node::new("127.0.0.1:19107".to_string()).await.unwrap();
+        let c = Node::new("127.0.0.1:19108".to_string()).await.unwrap();
+        let mut handles = a.start();
+        handles.extend(b.start());
+        a.router.lock().await.upsert(b.node_info.clone());
+        b.router.lock().await.upsert(c.node_info.clone());
+
+        // C is learned from B's FindNode response but is not answering yet
+        a.lookup(&c.node_info.id).await;
+        assert_eq!(a.router.lock().await.find(&c.node_info.id), None);
+        assert!(a
+            .unverified
+            .lock()
+            .await
+            .iter()
+            .any(|n| n.id == c.node_info.id));
+
+        // Once C answers the verifier's ping it joins the table
+        handles.extend(c.start());
+        let contacts: Vec<NodeInfo> = a.unverified.lock().await.drain(..).collect();
+        for contact in contacts {
+            a.clone().send(RequestPayload::Ping, &contact).await;
+        }
+        assert!(a.router.lock().await.find(&c.node_info.id).is_some());
+
+        for node in [&a, &b, &c] {
+            node.shutdown().await.unwrap();
+        }
+        for handle in handles {
+            handle.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn learn_limits() {
+        let node = Node::new("127.0.0.1:19109".to_string()).await.unwrap();
+        let contact = |address: &str| peer(address.parse().unwrap());
+
+        // Banned contacts are never queued
+        let banned = contact("1.1.1.1:8080");
+        node.ban(Peer::Id(banned.id.clone()), None).await;
+        node.learn(&[banned]).await;
+        assert!(node.unverified.lock().await.is_empty());
+
+        // A single subnet only holds as many places as it could take in the table
+        let flood: Vec<NodeInfo> = (0..100)
+            .map(|port| contact(&format!("2.2.2.2:{}", 1000 + port)))
+            .collect();
+        node.learn(&flood).await;
+        let per_table = node.config.diversity.per_table;
+        assert_eq!(node.unverified.lock().await.len(), per_table);
+
+        // Once full the oldest contacts make room for new ones
+        let many: Vec<NodeInfo> = (0..MAX_UNVERIFIED)
+            .map(|i| contact(&format!("10.{}.{}.1:8080", i / 256, i % 256)))
+            .collect();
+        node.learn(&many).await;
+        let unverified = node.unverified.lock().await;
+        assert_eq!(unverified.len(), MAX_UNVERIFIED);
+        assert!(unverified.iter().all(|n| !flood.contains(n)));
+        assert_eq!(unverified.back(), many.last());
+    }
+
+    /// Read a single unencrypted [`Message`] sent to `destination`
+    async fn receive(socket: &tokio::net::UdpSocket, destination: &Id) -> Message {
+        let mut buffer = [0; MESSAGE_SIZE];
+        let x = socket.recv(&mut buffer).await.unwrap();
+        let chunk = Chunk::decode(&buffer[..x]).unwrap();
+        assert_eq!(chunk.total, 1);
+        match Frame::decode(&chunk.data) {
+            Some(Frame::Plain(sealed)) => Message::open(&sealed, destination).unwrap().0,
+            _ => panic!("expected plain frame"),
+        }
+    }
+
+    /// Answer `request_id` with `response` from `source` to the node at `to`, unencrypted
+    async fn respond(
+        socket: &tokio::net::UdpSocket,
+        signing_key: &SigningKey,
+        source: &NodeInfo,
+        to: &NodeInfo,
+        request_id: Id,
+        response: ResponsePayload,
+    ) {
+        let message = Message::Response(ResponseHandle {
+            id: Id::random(),
+            source: source.clone(),
+            request_id,
+            observed: None,
+            response,
+        });
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let sealed = message.seal(signing_key, &to.id, now);
+        for chunk in Chunk::split(&Frame::Plain(sealed).encode()).unwrap() {
+            socket.send_to(&chunk.encode(), &to.address).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn advertised_address() {
+        let node = Node::new("127.0.0.1:19110".to_string()).await.unwrap();
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:19111")
+            .await
+            .unwrap();
+        let address = socket.local_addr().unwrap();
+        let remote = peer(address);
+        let public: SocketAddr = "1.2.3.4:8080".parse().unwrap();
+
+        // Requests and responses both carry the advertised address
+        let on_the_wire = || async {
+            let (mut sender, destination) = (node.clone(), remote.clone());
+            tokio::spawn(async move { sender.send(RequestPayload::Ping, &destination).await });
+            let sent = receive(&socket, &remote.id).await;
+            assert!(matches!(sent, Message::Request(_)));
+
+            node.clone()
+                .process_request(request(&remote, RequestPayload::Ping), address)
+                .await;
+            let response = receive(&socket, &remote.id).await;
+            assert!(matches!(response, Message::Response(_)));
+            assert_eq!(sent.source().address, response.source().address);
+            sent.source().address.clone()
+        };
+        assert_eq!(on_the_wire().await, node.node_info.address);
+
+        // Only contacts already in the table vote
+        let voters: Vec<NodeInfo> = (0..MIN_VOTES)
+            .map(|i| peer(SocketAddr::from(([10, 0, i as u8, 1], 8080))))
+            .collect();
+        for voter in voters.iter() {
+            node.vote(voter, public).await;
+        }
+        assert_eq!(node.external.lock().await.learned(), None);
+        assert_eq!(on_the_wire().await, node.node_info.address);
+
+        for voter in voters {
+            node.router.lock().await.upsert(voter.clone());
+            node.vote(&voter, public).await;
+        }
+        assert_eq!(on_the_wire().await, public.to_string());
+    }
+
+    #[tokio::test]
+    async fn publish_locally() {
+        let node = Node::new("127.0.0.1:19112".to_string()).await.unwrap();
+        assert!(node.config.republish_interval < node.config.default_record_ttl);
+
+        // With no one else to store it the publisher still holds its own record
+        let key = Id::from_key(b"owned");
+        assert_eq!(node.put(key.clone(), b"mine".to_vec(), None).await, 0);
+        let record = node.store.lock().await.record(&key).unwrap();
+        assert_eq!(record.value, b"mine");
+        assert_eq!(record.source, None);
+    }
+
+    #[tokio::test]
+    async fn hand_over_after_answer() {
+        let node = Node::new("127.0.0.1:19113".to_string()).await.unwrap();
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:19114")
+            .await
+            .unwrap();
+        let address = socket.local_addr().unwrap();
+        let remote_key = SigningKey::generate(&mut OsRng);
+        let remote = NodeInfo::new(&remote_key.verifying_key(), address.to_string());
+        // Keyed by the remote's own id so it is closer to the record than we are
+        node.store
+            .lock()
+            .await
+            .upsert(
+                remote.id.clone(),
+                Record::new(b"v".to_vec(), STALE_DURATION),
+            )
+            .unwrap();
+
+        // A request, whose source could be spoofed, is answered but the requester is only queued for
+        // verification and nothing is handed over
+        node.clone()
+            .process_request(request(&remote, RequestPayload::Ping), address)
+            .await;
+        assert!(matches!(
+            receive(&socket, &remote.id).await,
+            Message::Response(_)
+        ));
+        let mut buffer = [0; MESSAGE_SIZE];
+        let quiet = timeout(Duration::from_millis(200), socket.recv(&mut buffer)).await;
+        assert!(quiet.is_err());
+        assert_eq!(node.router.lock().await.find(&remote.id), None);
+        assert_eq!(node.unverified.lock().await.front(), Some(&remote));
+
+        // Once it answers the verifier from its address it joins the table and the record is handed over
+        let handles = node.start();
+        let request_id = match receive(&socket, &remote.id).await {
+            Message::Request(RequestHandle {
+                id,
+                request: RequestPayload::Ping,
+                ..
+            }) => id,
+            _ => panic!("expected ping"),
+        };
+        respond(
+            &socket,
+            &remote_key,
+            &remote,
+            &node.node_info,
+            request_id,
+            ResponsePayload::Pong,
+        )
+        .await;
+        let handed = timeout(Duration::from_secs(2), receive(&socket, &remote.id))
+            .await
+            .expect("expected the record to be handed over");
+        match handed {
+            Message::Request(RequestHandle {
+                request: RequestPayload::Store { key, value, .. },
+                ..
+            }) => assert_eq!((key, value), (remote.id.clone(), b"v".to_vec())),
+            _ => panic!("expected the record to be handed over"),
+        }
+        assert!(node.router.lock().await.find(&remote.id).is_some());
+
+        node.shutdown().await.unwrap();
+        for handle in handles {
+            handle.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn lookup_providers() {
+        let node = Node::new("127.0.0.1:19115".to_string()).await.unwrap();
+        let handles = node.start();
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:19116")
+            .await
+            .unwrap();
+        let remote_key = SigningKey::generate(&mut OsRng);
+        let remote = NodeInfo::new(&remote_key.verifying_key(), "127.0.0.1:19116".to_string());
+        node.router.lock().await.upsert(remote.clone());
+
+        let provider = |address: &str| {
+            NodeInfo::new(
+                &SigningKey::generate(&mut OsRng).verifying_key(),
+                address.to_string(),
+            )
+        };
+        let honest = provider("127.0.0.1:1");
+        let banned = provider("127.0.0.1:2");
+        node.ban(Peer::Id(banned.id.clone()), None).await;
+        let key = Id::random();
+        let lookup = {
+            let node = node.clone();
+            let key = key.clone();
+            tokio::spawn(async move { node.get_providers(&key).await })
+        };
+        let request_id = match receive(&socket, &remote.id).await {
+            Message::Request(RequestHandle {
+                id,
+                request: RequestPayload::GetProviders { .. },
+                ..
+            }) => id,
+            _ => panic!("expected get providers"),
+        };
+        let found = vec![
+            honest.clone(),
+            banned,
+            // Claims an id its key does not give it
+            NodeInfo::with_id(Id::random(), "127.0.0.1:3".to_string()),
+            // An address the diversity limits cannot be applied to
+            provider("localhost:4"),
+        ];
+        respond(
+            &socket,
+            &remote_key,
+            &remote,
+            &node.node_info,
+            request_id,
+            ResponsePayload::Providers {
+                providers: found,
+                closest: Vec::new(),
+            },
+        )
+        .await;
+
+        assert_eq!(lookup.await.unwrap(), vec![honest]);
+        let score = node.reputation.lock().unwrap().score(&Peer::Id(remote.id));
+        assert!(score < 0);
+
+        node.shutdown().await.unwrap();
+        for handle in handles {
+            handle.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn hostile_mutable_overwrite() {
+        let mut node = Node::new("127.0.0.1:19100".to_string()).await.unwrap();
+        let address: SocketAddr = "127.0.0.1:19101".parse().unwrap();
+        let attacker = peer(address);
+        let owner = SigningKey::generate(&mut OsRng);
+        let salt = b"cfg".to_vec();
+        let key = MutableRecord::key(&owner.verifying_key().to_bytes(), &salt);
+        let store = |value: Vec<u8>, namespace: Option<&str>| RequestPayload::Store {
+            key: key.clone(),
+            value,
+            ttl: None,
+            namespace: namespace.map(str::to_string),
+            cached: false,
+        };
+
+        let record = MutableRecord::new(&owner, salt.clone(), 2, b"v2".to_vec());
+        node.process_request(
+            request(&attacker, store(record.encode(), Some(MUTABLE_NAMESPACE))),
+            address,
+        )
+        .await;
+
+        // Unsigned values, values from another namespace, stale updates and updates signed by anyone else
+        // are all refused
+        let forger = SigningKey::generate(&mut OsRng);
+        let hostile = [
+            (b"evil".to_vec(), None),
+            (b"evil".to_vec(), Some(IMMUTABLE_NAMESPACE)),
+            (
+                MutableRecord::new(&owner, salt.clone(), 1, b"v1".to_vec()).encode(),
+                Some(MUTABLE_NAMESPACE),
+            ),
+            (
+                MutableRecord::new(&forger, salt.clone(), 3, b"evil".to_vec()).encode(),
+                Some(MUTABLE_NAMESPACE),
+            ),
+        ];
+        for (value, namespace) in hostile {
+            node.process_request(request(&attacker, store(value, namespace)), address)
+                .await;
+            let stored = node.store.lock().await.record(&key).unwrap();
+            assert_eq!(stored.value, record.encode());
+        }
+
+        let update = MutableRecord::new(&owner, salt, 3, b"v3".to_vec());
+        node.process_request(
+            request(&attacker, store(update.encode(), Some(MUTABLE_NAMESPACE))),
+            address,
+        )
+        .await;
+        let stored = node.store.lock().await.record(&key).unwrap();
+        assert_eq!(stored.value, update.encode());
+    }
+
+    #[tokio::test]
+    async fn immutable_store() {
+        let mut node = Node::new("127.0.0.1:19102".to_string()).await.unwrap();
+        let address: SocketAddr = "127.0.0.1:19103".parse().unwrap();
+        let sender = peer(address);
+        let key = ImmutableValidator::key(b"blob");
+        let store = |value: &[u8], namespace: Option<&str>| RequestPayload::Store {
+            key: key.clone(),
+            value: value.to_vec(),
+            ttl: None,
+            namespace: namespace.map(str::to_string),
+            cached: false,
+        };
+
+        node.process_request(
+            request(&sender, store(b"tampered", Some(IMMUTABLE_NAMESPACE))),
+            address,
+        )
+        .await;
+        assert_eq!(node.store.lock().await.record(&key), None);
+
+        node.process_request(
+            request(&sender, store(b"blob", Some(IMMUTABLE_NAMESPACE))),
+            address,
+        )
+        .await;
+        node.process_request(request(&sender, store(b"tampered", None)), address)
+            .await;
+        let stored = node.store.lock().await.record(&key).unwrap();
+        assert_eq!(stored.value, b"blob");
+    }
+}
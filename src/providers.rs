@@ -0,0 +1,340 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    diversity::Subnet, id::Id, kbucket::KBUCKET_MAX_LENGTH, routing::NodeInfo, storage::Rejection,
+};
+
+/// 24 hour duration before a provider is forgotten unless it announces itself again
+pub const PROVIDER_TTL: Duration = Duration::new(24 * 60 * 60, 0);
+
+/// Maximum number of providers remembered per key
+pub const MAX_PROVIDERS: usize = KBUCKET_MAX_LENGTH;
+
+/// Bounds on what [`Providers`] remembers, as [`crate::storage::StoreLimits`] are for records
+#[derive(Debug, Clone)]
+pub struct ProviderLimits {
+    /// Largest number of keys with providers
+    pub max_keys: usize,
+    /// Largest number of providers over every key
+    pub max_providers: usize,
+    /// Largest number of providers announced from a single IP address
+    pub max_source_providers: usize,
+    /// Largest number of providers of one key announced from a single [`Subnet`], so one party cannot take
+    /// every place a key has
+    pub max_subnet_providers: usize,
+}
+
+impl Default for ProviderLimits {
+    fn default() -> Self {
+        Self {
+            max_keys: 16 * 1024,
+            max_providers: 64 * 1024,
+            max_source_providers: 1024,
+            max_subnet_providers: 2,
+        }
+    }
+}
+
+/// A node that announced it can provide the content for a key
+#[derive(Debug, Clone)]
+struct Provider {
+    node_info: NodeInfo,
+    /// IP address the announcement came from, which it counts towards
+    /// [`ProviderLimits::max_source_providers`] of
+    origin: IpAddr,
+    /// [`Subnet`] of `origin` counted towards [`ProviderLimits::max_subnet_providers`], `None` when exempt
+    subnet: Option<Subnet>,
+    expires: SystemTime,
+}
+
+/// Who provides which keys, kept apart from the values in [`crate::storage::Store`]
+#[derive(Default)]
+pub struct Providers {
+    keys: HashMap<Id, Vec<Provider>>,
+    limits: ProviderLimits,
+    /// Number of providers over every key
+    total: usize,
+    /// Number of providers announced from each IP address
+    per_source: HashMap<IpAddr, usize>,
+}
+
+impl Providers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create `Providers` that never holds more than `limits`
+    pub fn with_limits(limits: ProviderLimits) -> Self {
+        Self {
+            limits,
+            ..Self::default()
+        }
+    }
+
+    /// Add or refresh a provider of `key` announced from `origin`, within `subnet` unless it is exempt from
+    /// the diversity limits, for `ttl`. Expired providers make way when the key is at [`MAX_PROVIDERS`], but
+    /// live ones are never pushed out, so a new provider of a full key or one over the [`ProviderLimits`] is
+    /// refused
+    pub fn add(
+        &mut self,
+        key: Id,
+        node_info: NodeInfo,
+        origin: IpAddr,
+        subnet: Option<Subnet>,
+        ttl: Duration,
+    ) -> Result<(), Rejection> {
+        let now = SystemTime::now();
+        let known = self.keys.get(&key);
+        let refresh = known.and_then(|ps| ps.iter().find(|p| p.node_info.id == node_info.id));
+        let live = known.map_or(0, |ps| ps.iter().filter(|p| p.expires > now).count());
+        let expired = known.map_or(0, |ps| ps.len()) - live;
+        if known.is_none() && self.keys.len() >= self.limits.max_keys {
+            return Err(Rejection::StoreFull);
+        }
+        if refresh.is_none() {
+            if live >= MAX_PROVIDERS {
+                return Err(Rejection::StoreFull);
+            }
+            if expired == 0 && self.total >= self.limits.max_providers {
+                return Err(Rejection::StoreFull);
+            }
+            let in_subnet = known.map_or(0, |ps| {
+                ps.iter()
+                    .filter(|p| p.expires > now && subnet.is_some() && p.subnet == subnet)
+                    .count()
+            });
+            if in_subnet >= self.limits.max_subnet_providers {
+                return Err(Rejection::QuotaExceeded);
+            }
+        }
+        let from_source = self.per_source.get(&origin).copied().unwrap_or(0);
+        if refresh.map(|p| p.origin) != Some(origin)
+            && from_source >= self.limits.max_source_providers
+        {
+            return Err(Rejection::QuotaExceeded);
+        }
+
+        let mut removed = Vec::new();
+        let providers = self.keys.entry(key).or_default();
+        if let Some(i) = providers
+            .iter()
+            .position(|p| p.node_info.id == node_info.id)
+        {
+            removed.push(providers.remove(i));
+        } else if let Some(i) = providers.iter().position(|p| p.expires <= now) {
+            removed.push(providers.remove(i));
+        }
+
+        providers.push(Provider {
+            node_info,
+            origin,
+            subnet,
+            expires: now + ttl,
+        });
+        *self.per_source.entry(origin).or_default() += 1;
+        self.total += 1;
+        for provider in removed {
+            self.forget(&provider);
+        }
+        Ok(())
+    }
+
+    /// Take a removed provider off the totals
+    fn forget(&mut self, provider: &Provider) {
+        self.total -= 1;
+        if let Some(count) = self.per_source.get_mut(&provider.origin) {
+            *count -= 1;
+            if *count == 0 {
+                self.per_source.remove(&provider.origin);
+            }
+        }
+    }
+
+    /// Unexpired providers of `key`
+    pub fn get(&self, key: &Id) -> Vec<NodeInfo> {
+        let now = SystemTime::now();
+        self.keys
+            .get(key)
+            .map(|providers| {
+                providers
+                    .iter()
+                    .filter(|p| p.expires > now)
+                    .map(|p| p.node_info.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Remove all expired providers
+    pub fn remove_stale(&mut self) {
+        let now = SystemTime::now();
+        let mut removed = Vec::new();
+        for providers in self.keys.values_mut() {
+            let (live, expired) = providers.drain(..).partition(|p| p.expires > now);
+            *providers = live;
+            removed.extend::<Vec<Provider>>(expired);
+        }
+        self.keys.retain(|_, providers| !providers.is_empty());
+        for provider in removed {
+            self.forget(&provider);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn node_info(port: u16) -> NodeInfo {
+        NodeInfo::with_id(Id::random(), format!("localhost:{}", port))
+    }
+
+    fn origin() -> IpAddr {
+        "1.1.1.1".parse().unwrap()
+    }
+
+    #[test]
+    fn add() {
+        let mut providers = Providers::new();
+        let key = Id::random();
+        let (a, b) = (node_info(8080), node_info(8081));
+        providers
+            .add(key.clone(), a.clone(), origin(), None, PROVIDER_TTL)
+            .unwrap();
+        providers
+            .add(key.clone(), a.clone(), origin(), None, PROVIDER_TTL)
+            .unwrap();
+        providers
+            .add(key.clone(), b.clone(), origin(), None, Duration::ZERO)
+            .unwrap();
+        assert_eq!(providers.get(&key), vec![a]);
+        assert!(providers.get(&Id::random()).is_empty());
+
+        providers.remove_stale();
+        assert_eq!(providers.keys[&key].len(), 1);
+        assert_eq!(providers.total, 1);
+        assert_eq!(providers.per_source[&origin()], 1);
+    }
+
+    #[test]
+    fn capacity() {
+        let mut providers = Providers::new();
+        let key = Id::random();
+        let expiring = node_info(8080);
+        providers
+            .add(
+                key.clone(),
+                expiring.clone(),
+                origin(),
+                None,
+                Duration::ZERO,
+            )
+            .unwrap();
+        for port in 8081..8080 + MAX_PROVIDERS as u16 {
+            providers
+                .add(key.clone(), node_info(port), origin(), None, PROVIDER_TTL)
+                .unwrap();
+        }
+
+        // An expired provider makes way, but live ones are never pushed out
+        let newcomer = node_info(9000);
+        providers
+            .add(key.clone(), newcomer.clone(), origin(), None, PROVIDER_TTL)
+            .unwrap();
+        assert_eq!(providers.total, MAX_PROVIDERS);
+        assert_eq!(
+            providers.add(key.clone(), node_info(9001), origin(), None, PROVIDER_TTL),
+            Err(Rejection::StoreFull)
+        );
+
+        let providers = providers.get(&key);
+        assert_eq!(providers.len(), MAX_PROVIDERS);
+        assert!(providers.contains(&newcomer));
+        assert!(!providers.contains(&expiring));
+    }
+
+    #[test]
+    fn subnets() {
+        let mut providers = Providers::new();
+        let key = Id::random();
+        let subnet = Some(Subnet::new(&origin()));
+        let max = ProviderLimits::default().max_subnet_providers;
+        let known = node_info(8080);
+        providers
+            .add(key.clone(), known.clone(), origin(), subnet, PROVIDER_TTL)
+            .unwrap();
+        for port in 8081..8080 + max as u16 {
+            providers
+                .add(key.clone(), node_info(port), origin(), subnet, PROVIDER_TTL)
+                .unwrap();
+        }
+        assert_eq!(
+            providers.add(key.clone(), node_info(9000), origin(), subnet, PROVIDER_TTL),
+            Err(Rejection::QuotaExceeded)
+        );
+
+        // Known providers refresh, other keys and other subnets are unaffected
+        providers
+            .add(key.clone(), known, origin(), subnet, PROVIDER_TTL)
+            .unwrap();
+        providers
+            .add(
+                Id::random(),
+                node_info(9000),
+                origin(),
+                subnet,
+                PROVIDER_TTL,
+            )
+            .unwrap();
+        let other: IpAddr = "2.2.2.2".parse().unwrap();
+        providers
+            .add(
+                key,
+                node_info(9001),
+                other,
+                Some(Subnet::new(&other)),
+                PROVIDER_TTL,
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn limits() {
+        let mut providers = Providers::with_limits(ProviderLimits {
+            max_keys: 2,
+            max_providers: 3,
+            max_source_providers: 2,
+            max_subnet_providers: 2,
+        });
+        let (a, b): (IpAddr, IpAddr) = ("1.1.1.1".parse().unwrap(), "2.2.2.2".parse().unwrap());
+        let (k1, k2) = (Id::random(), Id::random());
+        let n = node_info(8080);
+        providers
+            .add(k1.clone(), n.clone(), a, None, PROVIDER_TTL)
+            .unwrap();
+        providers
+            .add(k2.clone(), n.clone(), a, None, PROVIDER_TTL)
+            .unwrap();
+        assert_eq!(
+            providers.add(k1.clone(), node_info(8081), a, None, PROVIDER_TTL),
+            Err(Rejection::QuotaExceeded)
+        );
+        // Refreshing an existing provider is never over a limit
+        providers.add(k1.clone(), n, a, None, PROVIDER_TTL).unwrap();
+        assert_eq!(
+            providers.add(Id::random(), node_info(8082), b, None, PROVIDER_TTL),
+            Err(Rejection::StoreFull)
+        );
+        providers
+            .add(k1.clone(), node_info(8083), b, None, PROVIDER_TTL)
+            .unwrap();
+        assert_eq!(
+            providers.add(k2, node_info(8084), b, None, PROVIDER_TTL),
+            Err(Rejection::StoreFull)
+        );
+    }
+}
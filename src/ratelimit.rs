@@ -0,0 +1,261 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+    time::Instant,
+};
+
+use crate::diversity;
+
+/// Number of sources tracked before the least recently heard from is forgotten
+pub const MAX_TRACKED_SOURCES: usize = 4096;
+
+/// Tokens refilled at a steady rate up to a burst size, a request is allowed for each token taken
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    /// Tokens added per second
+    rate: f64,
+    /// Most tokens the bucket holds
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Create a full `TokenBucket`
+    pub fn new(rate: u32, burst: u32, now: Instant) -> Self {
+        Self::holding(rate, burst, burst, now)
+    }
+
+    /// Create a `TokenBucket` holding `tokens` of its `burst`
+    pub fn holding(rate: u32, burst: u32, tokens: u32, now: Instant) -> Self {
+        Self {
+            rate: rate as f64,
+            burst: burst as f64,
+            tokens: tokens.min(burst) as f64,
+            updated: now,
+        }
+    }
+
+    /// Tokens in the bucket at `now`
+    fn tokens(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * self.rate).min(self.burst)
+    }
+
+    /// Take a token, `false` if the bucket is empty
+    pub fn take(&mut self, now: Instant) -> bool {
+        self.tokens = self.tokens(now);
+        self.updated = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// Datagrams per second accepted from a single [`diversity::host`], checked before anything in them is decoded,
+/// and requests per second accepted from everyone together once they are authenticated
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimits {
+    pub per_source: u32,
+    /// Enough for the largest message split into [`crate::rpc::MAX_CHUNKS`] datagrams. A source starts with a
+    /// second of `per_source` and builds up to it, so new or forgotten sources cannot each send a full burst
+    pub per_source_burst: u32,
+    pub global: u32,
+    pub global_burst: u32,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            per_source: 200,
+            per_source_burst: 600,
+            global: 500,
+            global_burst: 1000,
+        }
+    }
+}
+
+/// Number of datagrams dropped by the per source limit and requests dropped by the global one
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Dropped {
+    pub per_source: u64,
+    pub global: u64,
+}
+
+/// Throttles inbound datagrams with a [`TokenBucket`] per source [`diversity::host`] and requests with one shared
+/// by all
+pub struct RateLimiter {
+    limits: RateLimits,
+    global: TokenBucket,
+    /// Bucket of each source host along with when it was last used, in ticks of `clock`
+    sources: HashMap<IpAddr, (TokenBucket, u64)>,
+    /// Sources by when they were last used, least recently first
+    recent: BTreeMap<u64, IpAddr>,
+    clock: u64,
+    dropped: Dropped,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            global: TokenBucket::new(limits.global, limits.global_burst, Instant::now()),
+            limits,
+            sources: HashMap::new(),
+            recent: BTreeMap::new(),
+            clock: 0,
+            dropped: Dropped::default(),
+        }
+    }
+
+    /// Check if a datagram from `source` may be handled at `now`, counting it as dropped otherwise. Beyond
+    /// [`MAX_TRACKED_SOURCES`] the least recently heard from source is forgotten
+    pub fn check_source(&mut self, source: IpAddr, now: Instant) -> bool {
+        let source = diversity::host(source);
+        self.clock += 1;
+        let limits = self.limits;
+        let bucket = match self.sources.get_mut(&source) {
+            Some((bucket, used)) => {
+                self.recent.remove(used);
+                *used = self.clock;
+                bucket
+            }
+            None => {
+                if self.sources.len() >= MAX_TRACKED_SOURCES {
+                    if let Some((_, oldest)) = self.recent.pop_first() {
+                        self.sources.remove(&oldest);
+                    }
+                }
+                let bucket = TokenBucket::holding(
+                    limits.per_source,
+                    limits.per_source_burst,
+                    limits.per_source,
+                    now,
+                );
+                &mut self.sources.entry(source).or_insert((bucket, self.clock)).0
+            }
+        };
+        self.recent.insert(self.clock, source);
+
+        if !bucket.take(now) {
+            self.dropped.per_source += 1;
+            return false;
+        }
+        true
+    }
+
+    /// Check if an authenticated request may be handled at `now`, counting it as dropped otherwise
+    pub fn check_global(&mut self, now: Instant) -> bool {
+        if !self.global.take(now) {
+            self.dropped.global += 1;
+            return false;
+        }
+        true
+    }
+
+    /// Datagrams and requests dropped since the `RateLimiter` was created
+    pub fn dropped(&self) -> Dropped {
+        self.dropped
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    /// Time for a [`TokenBucket`] to gain a single token
+    fn interval(rate: u32) -> Duration {
+        Duration::from_secs_f64(1.0 / rate as f64)
+    }
+
+    #[test]
+    fn token_bucket() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(10, 2, now);
+        assert!(bucket.take(now));
+        assert!(bucket.take(now));
+        assert!(!bucket.take(now));
+        assert!(bucket.take(now + interval(10)));
+        assert!(!bucket.take(now + interval(10)));
+
+        // Never refills past the burst size
+        let later = now + Duration::from_secs(60);
+        assert_eq!(bucket.tokens(later), 2.0);
+        assert!(bucket.take(later) && bucket.take(later) && !bucket.take(later));
+    }
+
+    #[test]
+    fn rate_limiter() {
+        let limits = RateLimits {
+            per_source: 1,
+            per_source_burst: 2,
+            global: 1,
+            global_burst: 1,
+        };
+        let mut limiter = RateLimiter::new(limits);
+        let now = Instant::now();
+        let (a, b): (IpAddr, IpAddr) = ("1.1.1.1".parse().unwrap(), "2.2.2.2".parse().unwrap());
+        // A new source starts with a second's worth rather than the whole burst
+        assert!(limiter.check_source(a, now));
+        assert!(!limiter.check_source(a, now));
+        let later = now + interval(1) * 2;
+        assert!(limiter.check_source(a, later));
+        assert!(limiter.check_source(a, later));
+        assert!(!limiter.check_source(a, later));
+        assert!(limiter.check_source(b, now));
+        assert!(limiter.check_global(now));
+        assert!(!limiter.check_global(now));
+        assert_eq!(
+            limiter.dropped(),
+            Dropped {
+                per_source: 2,
+                global: 1
+            }
+        );
+    }
+
+    #[test]
+    fn ipv6_sources() {
+        let limits = RateLimits {
+            per_source: 1,
+            per_source_burst: 1,
+            ..RateLimits::default()
+        };
+        let mut limiter = RateLimiter::new(limits);
+        let now = Instant::now();
+        let source = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert!(limiter.check_source(source("2001:db8::1"), now));
+        // Another address in the same /64 shares its bucket, another /64 does not
+        assert!(!limiter.check_source(source("2001:db8::ffff:2"), now));
+        assert!(limiter.check_source(source("2001:db8:0:1::1"), now));
+        // An IPv4 address mapped into IPv6 is the IPv4 address
+        assert!(limiter.check_source(source("1.2.3.4"), now));
+        assert!(!limiter.check_source(source("::ffff:1.2.3.4"), now));
+    }
+
+    #[test]
+    fn tracked_sources() {
+        let limits = RateLimits {
+            per_source: 1,
+            per_source_burst: 1,
+            ..RateLimits::default()
+        };
+        let mut limiter = RateLimiter::new(limits);
+        let now = Instant::now();
+        let source = |i: usize| IpAddr::from([10, (i >> 16) as u8, (i >> 8) as u8, i as u8]);
+        for i in 0..MAX_TRACKED_SOURCES {
+            assert!(limiter.check_source(source(i), now));
+        }
+        // Heard from again, so the first source is no longer the least recent
+        assert!(!limiter.check_source(source(0), now));
+
+        assert!(limiter.check_source(source(MAX_TRACKED_SOURCES), now));
+        assert_eq!(limiter.sources.len(), MAX_TRACKED_SOURCES);
+        assert_eq!(limiter.recent.len(), MAX_TRACKED_SOURCES);
+        assert!(limiter.sources.contains_key(&source(0)));
+        assert!(!limiter.sources.contains_key(&source(1)));
+    }
+}
@@ -0,0 +1,187 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey, PUBLIC_KEY_LENGTH};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    id::{Id, ID_SIZE},
+    validation::RecordValidator,
+};
+
+/// Namespace of [`MutableRecord`]s
+pub const MUTABLE_NAMESPACE: &str = "/mut/";
+
+/// Namespace of immutable records, whose key is the SHA-256 hash of their value
+pub const IMMUTABLE_NAMESPACE: &str = "/imm/";
+
+/// A record only the holder of a key pair can update, modelled on BitTorrent BEP44. It is stored under the
+/// hash of its public key and salt, and an update only replaces it with a higher sequence number
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MutableRecord {
+    pub public_key: [u8; PUBLIC_KEY_LENGTH],
+    pub salt: Vec<u8>,
+    pub seq: u64,
+    pub value: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl MutableRecord {
+    /// Sign a new `MutableRecord`
+    pub fn new(signing_key: &SigningKey, salt: Vec<u8>, seq: u64, value: Vec<u8>) -> Self {
+        let signature = signing_key
+            .sign(&Self::signed_bytes(&salt, seq, &value))
+            .to_bytes()
+            .to_vec();
+
+        Self {
+            public_key: signing_key.verifying_key().to_bytes(),
+            salt,
+            seq,
+            value,
+            signature,
+        }
+    }
+
+    /// The [`Id`] records for a public key and salt are stored under
+    pub fn key(public_key: &[u8; PUBLIC_KEY_LENGTH], salt: &[u8]) -> Id {
+        let mut buffer = public_key.to_vec();
+        buffer.extend_from_slice(salt);
+        Id::from_key(&buffer)
+    }
+
+    /// Bencoded salt, sequence number and value the signature covers, as in BEP44
+    fn signed_bytes(salt: &[u8], seq: u64, value: &[u8]) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        if !salt.is_empty() {
+            buffer.extend_from_slice(format!("4:salt{}:", salt.len()).as_bytes());
+            buffer.extend_from_slice(salt);
+        }
+        buffer.extend_from_slice(format!("3:seqi{}e1:v{}:", seq, value.len()).as_bytes());
+        buffer.extend_from_slice(value);
+        buffer
+    }
+
+    /// Check that the signature was made by the public key
+    pub fn verify(&self) -> Result<(), String> {
+        let public_key = VerifyingKey::from_bytes(&self.public_key).map_err(|e| e.to_string())?;
+        let signature = Signature::from_slice(&self.signature).map_err(|e| e.to_string())?;
+        public_key
+            .verify(
+                &Self::signed_bytes(&self.salt, self.seq, &self.value),
+                &signature,
+            )
+            .map_err(|_| "bad signature".to_string())
+    }
+
+    /// Serialized form stored as the value of a record
+    pub fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("failed to serialize mutable record")
+    }
+
+    /// Parse a value stored in [`MUTABLE_NAMESPACE`]
+    pub fn decode(value: &[u8]) -> Result<Self, String> {
+        serde_json::from_slice(value).map_err(|e| e.to_string())
+    }
+}
+
+/// [`RecordValidator`] for [`MUTABLE_NAMESPACE`]
+pub struct MutableValidator;
+
+impl RecordValidator for MutableValidator {
+    fn validate(&self, key: &Id, value: &[u8]) -> Result<(), String> {
+        let record = MutableRecord::decode(value)?;
+        if &MutableRecord::key(&record.public_key, &record.salt) != key {
+            return Err("key does not match public key and salt".to_string());
+        }
+        record.verify()
+    }
+
+    /// Prefer the highest sequence number, the earliest value wins a tie so stale updates are refused
+    fn select(&self, _key: &Id, values: &[Vec<u8>]) -> usize {
+        let mut best = (0, None);
+        for (i, value) in values.iter().enumerate() {
+            let seq = MutableRecord::decode(value).ok().map(|r| r.seq);
+            if seq > best.1 {
+                best = (i, seq);
+            }
+        }
+        best.0
+    }
+}
+
+/// [`RecordValidator`] for [`IMMUTABLE_NAMESPACE`]
+pub struct ImmutableValidator;
+
+impl ImmutableValidator {
+    /// The [`Id`] an immutable value is stored under, its SHA-256 hash cut down to the size of an `Id`. SHA-1,
+    /// which other keys are hashed with, has practical collisions that would let a value be swapped out
+    pub fn key(value: &[u8]) -> Id {
+        let hash = Sha256::digest(value);
+        Id::new(
+            hash[..ID_SIZE]
+                .try_into()
+                .expect("sha-256 is longer than an id"),
+        )
+    }
+}
+
+impl RecordValidator for ImmutableValidator {
+    fn validate(&self, key: &Id, value: &[u8]) -> Result<(), String> {
+        match &Self::key(value) == key {
+            true => Ok(()),
+            false => Err("key is not the hash of the value".to_string()),
+        }
+    }
+
+    /// Every valid value for a key is identical
+    fn select(&self, _key: &Id, _values: &[Vec<u8>]) -> usize {
+        0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::rngs::OsRng;
+
+    #[test]
+    fn mutable() {
+        let signing_key = SigningKey::generate(&mut OsRng);
+        let record = MutableRecord::new(&signing_key, b"cfg".to_vec(), 1, b"v1".to_vec());
+        let key = MutableRecord::key(&record.public_key, b"cfg");
+        assert!(MutableValidator.validate(&key, &record.encode()).is_ok());
+        assert!(MutableValidator
+            .validate(&Id::random(), &record.encode())
+            .is_err());
+
+        let mut forged = record.clone();
+        forged.value = b"evil".to_vec();
+        assert!(MutableValidator.validate(&key, &forged.encode()).is_err());
+
+        let other = SigningKey::generate(&mut OsRng);
+        let mut resigned = MutableRecord::new(&other, b"cfg".to_vec(), 2, b"evil".to_vec());
+        resigned.public_key = record.public_key;
+        assert!(MutableValidator.validate(&key, &resigned.encode()).is_err());
+    }
+
+    #[test]
+    fn immutable() {
+        let key = ImmutableValidator::key(b"blob");
+        assert!(ImmutableValidator.validate(&key, b"blob").is_ok());
+        assert!(ImmutableValidator.validate(&key, b"tampered").is_err());
+        assert!(ImmutableValidator
+            .validate(&Id::from_key(b"blob"), b"blob")
+            .is_err());
+    }
+
+    #[test]
+    fn select() {
+        let signing_key = SigningKey::generate(&mut OsRng);
+        let v1 = MutableRecord::new(&signing_key, Vec::new(), 1, b"v1".to_vec()).encode();
+        let v2 = MutableRecord::new(&signing_key, Vec::new(), 2, b"v2".to_vec()).encode();
+        let other = MutableRecord::new(&signing_key, Vec::new(), 2, b"other".to_vec()).encode();
+        let key = Id::random();
+        assert_eq!(MutableValidator.select(&key, &[v1.clone(), v2.clone()]), 1);
+        assert_eq!(MutableValidator.select(&key, &[v2.clone(), v1]), 0);
+        assert_eq!(MutableValidator.select(&key, &[v2, other]), 0);
+    }
+}
@@ -0,0 +1,211 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{diversity, id::Id};
+
+/// Score below which a peer is banned
+pub const BAN_THRESHOLD: i32 = -100;
+
+/// Time a peer stays banned once its score falls below [`BAN_THRESHOLD`]
+pub const BAN_DURATION: Duration = Duration::new(60 * 60, 0);
+
+/// Time for a penalised peer to win back a single point
+pub const RECOVERY_INTERVAL: Duration = Duration::new(60, 0);
+
+/// [`Reputation`] shared between the [`crate::rpc::Rpc`] receiver, the [`crate::routing::RoutingTable`] and the
+/// node. Only held briefly and never across an await, so it is a blocking mutex
+pub type SharedReputation = Arc<Mutex<Reputation>>;
+
+/// A peer known by its [`Id`], or by its address before it has proven one. Addresses are made with
+/// [`Peer::address`] so a peer cannot get around a ban by changing port or, over IPv6, its address in a /64
+#[derive(PartialEq, Eq, Hash, Debug, Clone)]
+pub enum Peer {
+    Id(Id),
+    Address(IpAddr),
+}
+
+impl Peer {
+    /// The peer at `ip`, banned along with the rest of its [`diversity::host`]
+    pub fn address(ip: IpAddr) -> Self {
+        Peer::Address(diversity::host(ip))
+    }
+}
+
+/// Misbehaviour a peer is penalised for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Offence {
+    /// Answered one of our requests with a response of the wrong kind
+    Malformed,
+    /// Asked us to store a record its validator refuses
+    InvalidRecord,
+    /// Replied to a lookup with contacts whose [`Id`] is not derived from their key or misses the difficulty
+    BadContacts,
+}
+
+impl Offence {
+    /// Points taken off a peer's score
+    pub fn penalty(&self) -> i32 {
+        match self {
+            Offence::Malformed => 10,
+            Offence::InvalidRecord => 20,
+            Offence::BadContacts => 20,
+        }
+    }
+}
+
+/// A peer's score when last penalised
+#[derive(Debug, Clone, Copy)]
+struct Score {
+    value: i32,
+    updated: Instant,
+}
+
+impl Score {
+    /// The score after recovering for the time since it was last penalised
+    fn recovered(&self, now: Instant) -> i32 {
+        let points = now.duration_since(self.updated).as_secs() / RECOVERY_INTERVAL.as_secs();
+        self.value
+            .saturating_add(points.min(i32::MAX as u64) as i32)
+            .min(0)
+    }
+}
+
+/// Scores of peers that have misbehaved and the peers currently banned
+#[derive(Debug)]
+pub struct Reputation {
+    scores: HashMap<Peer, Score>,
+    /// When each ban ends
+    bans: HashMap<Peer, Instant>,
+    threshold: i32,
+    ban_duration: Duration,
+}
+
+impl Default for Reputation {
+    fn default() -> Self {
+        Self::new(BAN_THRESHOLD, BAN_DURATION)
+    }
+}
+
+impl Reputation {
+    /// Create a new `Reputation` banning peers for `ban_duration` once their score falls below `threshold`
+    pub fn new(threshold: i32, ban_duration: Duration) -> Self {
+        Self {
+            scores: HashMap::new(),
+            bans: HashMap::new(),
+            threshold,
+            ban_duration,
+        }
+    }
+
+    /// Current score of a peer, zero unless it has misbehaved recently
+    pub fn score(&self, peer: &Peer) -> i32 {
+        self.scores
+            .get(peer)
+            .map(|score| score.recovered(Instant::now()))
+            .unwrap_or(0)
+    }
+
+    /// Penalise a peer for an [`Offence`], returning `true` if this got it banned
+    pub fn penalise(&mut self, peer: Peer, offence: Offence) -> bool {
+        let now = Instant::now();
+        self.scores.retain(|_, score| score.recovered(now) < 0);
+
+        let value = self.score(&peer) - offence.penalty();
+        if value < self.threshold {
+            self.scores.remove(&peer);
+            self.ban(peer, self.ban_duration);
+            return true;
+        }
+
+        self.scores.insert(
+            peer,
+            Score {
+                value,
+                updated: now,
+            },
+        );
+        false
+    }
+
+    /// Ban a peer for `duration`, replacing any ban it already has
+    pub fn ban(&mut self, peer: Peer, duration: Duration) {
+        let now = Instant::now();
+        self.bans.retain(|_, until| *until > now);
+        self.bans.insert(peer, now + duration);
+    }
+
+    /// Lift a peer's ban and forget its score
+    pub fn unban(&mut self, peer: &Peer) {
+        self.bans.remove(peer);
+        self.scores.remove(peer);
+    }
+
+    /// Check if a peer is currently banned
+    pub fn is_banned(&self, peer: &Peer) -> bool {
+        self.bans
+            .get(peer)
+            .map(|until| *until > Instant::now())
+            .unwrap_or(false)
+    }
+
+    /// Check if a node is banned by either its [`Id`] or the IP address of its `ip:port` address
+    pub fn is_banned_node(&self, id: &Id, address: &str) -> bool {
+        self.is_banned(&Peer::Id(id.clone()))
+            || address
+                .parse::<SocketAddr>()
+                .map(|address| self.is_banned(&Peer::address(address.ip())))
+                .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn penalise() {
+        let mut reputation = Reputation::new(-30, BAN_DURATION);
+        let peer = Peer::Id(Id::random());
+        assert!(!reputation.penalise(peer.clone(), Offence::Malformed));
+        assert!(!reputation.penalise(peer.clone(), Offence::InvalidRecord));
+        assert_eq!(reputation.score(&peer), -30);
+        assert!(!reputation.is_banned(&peer));
+
+        assert!(reputation.penalise(peer.clone(), Offence::Malformed));
+        assert!(reputation.is_banned(&peer));
+        assert_eq!(reputation.score(&peer), 0);
+
+        reputation.unban(&peer);
+        assert!(!reputation.is_banned(&peer));
+    }
+
+    #[test]
+    fn ban() {
+        let mut reputation = Reputation::default();
+        let id = Id::random();
+        reputation.ban(Peer::address("1.2.3.4".parse().unwrap()), BAN_DURATION);
+        assert!(reputation.is_banned_node(&id, "1.2.3.4:8080"));
+        assert!(reputation.is_banned_node(&id, "1.2.3.4:8081"));
+        assert!(reputation.is_banned_node(&id, "[::ffff:1.2.3.4]:8080"));
+        assert!(!reputation.is_banned_node(&id, "1.2.3.5:8080"));
+        assert!(!reputation.is_banned_node(&id, "localhost:8080"));
+
+        // An IPv6 peer is banned with the rest of its /64
+        reputation.ban(Peer::address("2001:db8::1".parse().unwrap()), BAN_DURATION);
+        assert!(reputation.is_banned_node(&id, "[2001:db8::ffff:1]:8080"));
+        assert!(!reputation.is_banned_node(&id, "[2001:db8:0:1::1]:8080"));
+
+        reputation.ban(Peer::Id(id.clone()), Duration::ZERO);
+        assert!(!reputation.is_banned(&Peer::Id(id)));
+
+        let score = Score {
+            value: -10,
+            updated: Instant::now() - RECOVERY_INTERVAL * 4,
+        };
+        assert_eq!(score.recovered(Instant::now()), -6);
+    }
+}
@@ -1,50 +1,210 @@
-use std::{cmp, collections::VecDeque, net::SocketAddr, ops::Index, sync::Arc};
+use std::{
+    cmp,
+    collections::HashMap,
+    ops::Index,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
+use ed25519_dalek::{VerifyingKey, PUBLIC_KEY_LENGTH};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    id::Id,
+    diversity::{DiversityLimits, Subnet},
+    id::{Difficulty, Id, ID_SIZE},
     kbucket::{KBucket, KBUCKET_MAX_LENGTH},
+    reputation::SharedReputation,
 };
 
 // Maximum number of KBuckets in the routing table
 pub const ROUTING_TABLE_MAX_LENGTH: usize = 15;
 
+/// Failed requests in a row after which a restored contact is not worth verifying
+pub const MAX_FAILURES: u32 = 5;
+
 #[derive(PartialEq, Eq, Deserialize, Serialize, Debug, Clone)]
 pub struct NodeInfo {
+    /// Hash of `public_key`, see [`NodeInfo::verify`]
     pub id: Id,
+    /// Ed25519 public key identifying the node
+    pub public_key: [u8; PUBLIC_KEY_LENGTH],
     /// In the form `ip:port`
     pub address: String,
+    /// Solution of the dynamic crypto puzzle for `id`, see [`Difficulty`]
+    pub puzzle: Id,
+}
+
+impl NodeInfo {
+    /// Create a `NodeInfo` whose [`Id`] is derived from its public key
+    pub fn new(public_key: &VerifyingKey, address: String) -> Self {
+        let public_key = public_key.to_bytes();
+        Self {
+            id: Id::from_key(&public_key),
+            public_key,
+            address,
+            puzzle: Id::new([0; ID_SIZE]),
+        }
+    }
+
+    /// Check that the [`Id`] is the hash of the public key, so a node cannot choose its own `Id`
+    pub fn verify(&self) -> bool {
+        Id::from_key(&self.public_key) == self.id
+    }
+
+    /// A `NodeInfo` with a chosen [`Id`] and no key, for tests that only care where a node lands
+    #[cfg(test)]
+    pub(crate) fn with_id(id: Id, address: String) -> Self {
+        Self {
+            id,
+            public_key: [0; PUBLIC_KEY_LENGTH],
+            address,
+            puzzle: Id::new([0; ID_SIZE]),
+        }
+    }
+}
+
+/// When a node in the [`RoutingTable`] was last heard from and how many requests to it failed since
+#[derive(PartialEq, Eq, Deserialize, Serialize, Debug, Clone, Copy, Default)]
+pub struct Liveness {
+    /// Seconds since the unix epoch
+    pub last_seen: u64,
+    pub failures: u32,
 }
 
-#[derive(Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct RoutingTable {
     kbuckets: Vec<KBucket>,
     node_info: NodeInfo,
+    /// Crypto puzzles a node's [`Id`] must solve to be admitted
+    #[serde(default)]
+    difficulty: Difficulty,
+    /// Limits on nodes sharing a [`Subnet`]
+    #[serde(default)]
+    diversity: DiversityLimits,
+    /// Banned nodes are never admitted
+    #[serde(skip)]
+    reputation: SharedReputation,
+    /// [`Liveness`] of every node in the table, kept in a [`Snapshot`] as JSON maps need string keys
+    #[serde(skip)]
+    liveness: HashMap<Id, Liveness>,
 }
 
 impl RoutingTable {
     /// Create a new `RoutingTable` with a single empty [`KBucket`]
     pub fn new(node_info: NodeInfo) -> Self {
+        Self::with_limits(
+            node_info,
+            Difficulty::default(),
+            DiversityLimits::default(),
+            SharedReputation::default(),
+        )
+    }
+
+    /// Create a new `RoutingTable` that only admits nodes meeting `difficulty` and `diversity` that `reputation`
+    /// has not banned
+    pub fn with_limits(
+        node_info: NodeInfo,
+        difficulty: Difficulty,
+        diversity: DiversityLimits,
+        reputation: SharedReputation,
+    ) -> Self {
         Self {
             kbuckets: vec![KBucket::new()],
-            node_info: node_info,
+            node_info,
+            difficulty,
+            diversity,
+            reputation,
+            liveness: HashMap::new(),
         }
     }
 
-    /// Upsert a node into the `RoutingTable`, splitting [`KBucket`] as nessesary
+    /// Upsert a node into the `RoutingTable`, splitting [`KBucket`] as nessesary, and mark it as just seen.
+    /// Nodes whose [`Id`] does not meet the table's [`Difficulty`], whose [`Subnet`] is already at its
+    /// [`DiversityLimits`], or that are banned are refused
     pub fn upsert(&mut self, node_info: NodeInfo) -> bool {
+        let id = node_info.id.clone();
+        if !self.insert(node_info) {
+            return false;
+        }
+
+        self.liveness.insert(
+            id,
+            Liveness {
+                last_seen: unix_time(),
+                failures: 0,
+            },
+        );
+        true
+    }
+
+    /// Record a request to a node that went unanswered
+    pub fn failed(&mut self, id: &Id) {
+        if let Some(liveness) = self.liveness.get_mut(id) {
+            liveness.failures += 1;
+        }
+    }
+
+    /// [`Liveness`] of a node in the `RoutingTable`
+    pub fn liveness(&self, id: &Id) -> Option<Liveness> {
+        self.liveness.get(id).copied()
+    }
+
+    /// Check if a node could be upserted as far as the whole `RoutingTable` is concerned, it meets the
+    /// [`Difficulty`], is not banned and its [`Subnet`] is below the table's [`DiversityLimits`]. It may still
+    /// be refused by its [`KBucket`]
+    pub fn admits(&self, node_info: &NodeInfo) -> bool {
+        self.admission(node_info).is_ok()
+    }
+
+    /// The [`Subnet`] limiting a node the table admits, see [`RoutingTable::admits`]
+    fn admission(&self, node_info: &NodeInfo) -> Result<Option<Subnet>, ()> {
+        if !self.difficulty.check(&node_info.id, &node_info.puzzle) {
+            return Err(());
+        }
+        {
+            let reputation = self.reputation.lock().expect("reputation lock poisoned");
+            if reputation.is_banned_node(&node_info.id, &node_info.address) {
+                return Err(());
+            }
+        }
+
+        let subnet = self.diversity.subnet(&node_info.address).map_err(|_| ())?;
+        if let Some(subnet) = subnet {
+            let in_table = self
+                .kbuckets
+                .iter()
+                .map(|kb| self.in_subnet(kb, &subnet, &node_info.id))
+                .sum::<usize>();
+            if in_table >= self.diversity.per_table {
+                return Err(());
+            }
+        }
+        Ok(subnet)
+    }
+
+    fn insert(&mut self, node_info: NodeInfo) -> bool {
+        let subnet = match self.admission(&node_info) {
+            Ok(subnet) => subnet,
+            Err(_) => return false,
+        };
+
         let mut index = cmp::min(
             self.node_info.id.distance(&node_info.id),
             self.kbuckets.len() - 1,
         );
 
-        if self.kbuckets[index].contains(&node_info) {
+        if self.kbuckets[index].find(&node_info.id).is_some() {
             self.kbuckets[index].upsert(node_info);
-            return true;
+            true
         } else {
             loop {
                 if self.kbuckets[index].size() < KBUCKET_MAX_LENGTH {
+                    if let Some(subnet) = subnet {
+                        let in_bucket =
+                            self.in_subnet(&self.kbuckets[index], &subnet, &node_info.id);
+                        if in_bucket >= self.diversity.per_bucket {
+                            return false;
+                        }
+                    }
                     self.kbuckets[index].upsert(node_info.clone());
                     return true;
                 }
@@ -68,6 +228,13 @@ impl RoutingTable {
         }
     }
 
+    /// Number of nodes in a [`KBucket`] other than `id` from `subnet`
+    fn in_subnet(&self, kb: &KBucket, subnet: &Subnet, id: &Id) -> usize {
+        kb.0.iter()
+            .filter(|n| &n.id != id && self.diversity.subnet(&n.address) == Ok(Some(*subnet)))
+            .count()
+    }
+
     /// Find a [`NodeInfo`] in the `RoutingTable` from an `Id`
     pub fn find(&self, id: &Id) -> Option<NodeInfo> {
         for kb in self.kbuckets.iter() {
@@ -82,33 +249,42 @@ impl RoutingTable {
 
     /// Get the `n` closest nodes to [`Id`]
     pub fn closest(&self, id: &Id, n: usize) -> Vec<NodeInfo> {
-        let mut index = cmp::min(
-            self.node_info.id.distance(&self.node_info.id),
-            self.kbuckets.len() - 1,
-        );
-
-        let mut closest = Vec::new();
-        closest.extend_from_slice(self.kbuckets[index].0.clone().make_contiguous());
-
-        while index < self.kbuckets.len() && closest.len() < n {
-            for i in (index + 1)..self.kbuckets.len() {
-                let required = std::cmp::min(self.kbuckets[i].size(), n - closest.len());
-                closest
-                    .extend_from_slice(&self.kbuckets[i].0.clone().make_contiguous()[..required]);
-            }
-            index += 1
-        }
-
-        closest.sort_by_key(|node_info| node_info.id.distance(id));
+        // The table is bounded to `ROUTING_TABLE_MAX_LENGTH * KBUCKET_MAX_LENGTH` nodes so ranking all is cheap
+        let mut closest = self.nodes();
+        closest.sort_by_key(|node_info| node_info.id.xor(id));
+        closest.truncate(n);
 
         closest
     }
 
+    /// Number of nodes in the `RoutingTable` that are closer to `id` than the owning node is
+    pub fn closer(&self, id: &Id) -> usize {
+        let distance = self.node_info.id.xor(id);
+        self.kbuckets
+            .iter()
+            .flat_map(|kb| kb.0.iter())
+            .filter(|node_info| node_info.id.xor(id) < distance)
+            .count()
+    }
+
     /// Number of [`KBuckets`] in the `RoutingTable`
     pub fn size(&self) -> usize {
         self.kbuckets.len()
     }
 
+    /// Every [`NodeInfo`] in the `RoutingTable`, least recently seen first within each [`KBucket`]
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        self.kbuckets
+            .iter()
+            .flat_map(|kb| kb.0.iter().cloned())
+            .collect()
+    }
+
+    /// The [`NodeInfo`] of the node that owns the `RoutingTable`
+    pub fn node_info(&self) -> &NodeInfo {
+        &self.node_info
+    }
+
     /// Remove a node from the `RoutingTable` and return it
     pub fn remove(&mut self, node_info: &NodeInfo) -> Option<NodeInfo> {
         let idx = std::cmp::min(
@@ -116,51 +292,268 @@ impl RoutingTable {
             self.kbuckets.len() - 1,
         );
 
+        self.liveness.remove(&node_info.id);
         self.kbuckets[idx].remove(node_info)
     }
 }
 
+/// On-disk copy of a [`RoutingTable`] used to warm restart a node
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Snapshot {
+    /// Seconds since the unix epoch when the snapshot was taken
+    pub taken_at: u64,
+    /// Buckets keep their least recently seen ordering
+    pub table: RoutingTable,
+    /// [`Liveness`] of the nodes in `table`
+    #[serde(default)]
+    pub liveness: Vec<(Id, Liveness)>,
+}
+
+impl Snapshot {
+    /// Take a `Snapshot` of a [`RoutingTable`]
+    pub fn new(table: &RoutingTable) -> Self {
+        Self {
+            taken_at: unix_time(),
+            table: table.clone(),
+            liveness: table
+                .liveness
+                .iter()
+                .map(|(id, liveness)| (id.clone(), *liveness))
+                .collect(),
+        }
+    }
+
+    /// Nodes worth verifying on restart, most recently seen first, leaving out those that failed
+    /// [`MAX_FAILURES`] requests in a row
+    pub fn contacts(&self) -> Vec<NodeInfo> {
+        let liveness: HashMap<&Id, &Liveness> =
+            self.liveness.iter().map(|(id, l)| (id, l)).collect();
+        let mut contacts: Vec<(NodeInfo, Liveness)> = self
+            .table
+            .nodes()
+            .into_iter()
+            .map(|n| {
+                let l = liveness.get(&n.id).map(|l| **l).unwrap_or_default();
+                (n, l)
+            })
+            .filter(|(_, l)| l.failures < MAX_FAILURES)
+            .collect();
+        contacts.sort_by_key(|(_, l)| cmp::Reverse(l.last_seen));
+        contacts.into_iter().map(|(n, _)| n).collect()
+    }
+}
+
+/// Seconds since the unix epoch
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
 impl Index<Id> for RoutingTable {
     type Output = KBucket;
     fn index(&self, id: Id) -> &Self::Output {
         let idx = cmp::min(self.node_info.id.distance(&id), self.kbuckets.len() - 1);
 
-        return &self.kbuckets[idx];
+        &self.kbuckets[idx]
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use ed25519_dalek::SigningKey;
+    use rand::rngs::OsRng;
 
     #[test]
     fn index() {
-        let mut rt = RoutingTable::new(NodeInfo {
-            id: Id::new([1u8; 20]),
-            address: "localhost:8080".to_string(),
-        });
-
-        let n1 = NodeInfo {
-            id: Id::new([3u8; 20]),
-            address: "localhost:8081".to_string(),
-        };
+        let mut rt = RoutingTable::new(NodeInfo::with_id(
+            Id::new([1u8; 20]),
+            "127.0.0.1:8080".to_string(),
+        ));
+
+        let n1 = NodeInfo::with_id(Id::new([3u8; 20]), "127.0.0.1:8081".to_string());
         rt.upsert(n1);
         println!("{:?}", rt)
     }
 
     #[test]
     fn find() {
-        let mut rt = RoutingTable::new(NodeInfo {
-            id: Id::random(),
-            address: "localhost:8080".to_string(),
-        });
+        let mut rt = RoutingTable::new(NodeInfo::with_id(
+            Id::random(),
+            "127.0.0.1:8080".to_string(),
+        ));
 
         let id = Id::random();
-        let n1 = NodeInfo {
-            id: id.clone(),
-            address: "localhost:8081".to_string(),
-        };
+        let n1 = NodeInfo::with_id(id.clone(), "127.0.0.1:8081".to_string());
         rt.upsert(n1.clone());
         assert_eq!(rt.find(&id), Some(n1))
     }
+
+    #[test]
+    fn closest() {
+        let mut rt = RoutingTable::new(NodeInfo::with_id(
+            Id::random(),
+            "127.0.0.1:8080".to_string(),
+        ));
+        for port in 8081..8121 {
+            rt.upsert(NodeInfo::with_id(
+                Id::random(),
+                format!("127.0.0.1:{}", port),
+            ));
+        }
+
+        let id = Id::random();
+        let closest = rt.closest(&id, KBUCKET_MAX_LENGTH);
+        assert_eq!(closest.len(), KBUCKET_MAX_LENGTH);
+        assert!(closest
+            .windows(2)
+            .all(|w| w[0].id.xor(&id) <= w[1].id.xor(&id)));
+
+        let furthest = closest.last().unwrap().id.xor(&id);
+        assert!(rt
+            .nodes()
+            .iter()
+            .filter(|n| !closest.contains(n))
+            .all(|n| n.id.xor(&id) >= furthest));
+    }
+
+    #[test]
+    fn closer() {
+        let mut rt = RoutingTable::new(NodeInfo::with_id(
+            Id::new([0x0F; 20]),
+            "127.0.0.1:8080".to_string(),
+        ));
+        for (port, byte) in [(8081, 0x00), (8082, 0x01), (8083, 0xF0)] {
+            rt.upsert(NodeInfo::with_id(
+                Id::new([byte; 20]),
+                format!("127.0.0.1:{}", port),
+            ));
+        }
+
+        assert_eq!(rt.closer(&Id::new([0x0F; 20])), 0);
+        assert_eq!(rt.closer(&Id::new([0x00; 20])), 2);
+        assert_eq!(rt.closer(&Id::new([0xFF; 20])), 1);
+    }
+
+    #[test]
+    fn verify() {
+        let signing_key = SigningKey::generate(&mut OsRng);
+        let mut node_info =
+            NodeInfo::new(&signing_key.verifying_key(), "127.0.0.1:8080".to_string());
+        assert!(node_info.verify());
+
+        node_info.id = Id::random();
+        assert!(!node_info.verify());
+    }
+
+    #[test]
+    fn difficulty() {
+        let difficulty = Difficulty {
+            static_bits: 0,
+            dynamic_bits: 8,
+        };
+        let mut node_info = NodeInfo::with_id(Id::random(), "127.0.0.1:8080".to_string());
+        node_info.puzzle = node_info.id.solve(8);
+        let mut rt = RoutingTable::with_limits(
+            node_info.clone(),
+            difficulty,
+            DiversityLimits::default(),
+            SharedReputation::default(),
+        );
+
+        let mut solved = NodeInfo::with_id(Id::random(), "127.0.0.1:8081".to_string());
+        solved.puzzle = solved.id.solve(8);
+        let unsolved = (0..)
+            .map(|_| Id::random())
+            .find(|id| id.dynamic_puzzle(&solved.puzzle) < 8)
+            .unwrap();
+        assert!(rt.upsert(solved.clone()));
+        assert!(!rt.upsert(NodeInfo {
+            id: unsolved,
+            ..solved.clone()
+        }));
+        assert_eq!(rt.nodes(), vec![solved]);
+    }
+
+    #[test]
+    fn diversity() {
+        let node_info = |id: Id, address: &str| NodeInfo::with_id(id, address.to_string());
+        let limits = DiversityLimits {
+            per_bucket: 2,
+            per_table: 3,
+            allowlist: vec!["10.0.0.0/8".parse().unwrap()],
+        };
+        let mut rt = RoutingTable::with_limits(
+            node_info(Id::new([0x00; 20]), "1.1.1.1:8080"),
+            Difficulty::default(),
+            limits,
+            SharedReputation::default(),
+        );
+
+        // Both land in the bucket of nodes with no prefix in common
+        assert!(rt.upsert(node_info(Id::new([0xF0; 20]), "6.6.6.1:8080")));
+        assert!(rt.upsert(node_info(Id::new([0xF1; 20]), "6.6.6.2:8080")));
+        assert!(!rt.upsert(node_info(Id::new([0xF2; 20]), "6.6.6.3:8080")));
+        assert!(rt.upsert(node_info(Id::new([0xF2; 20]), "6.6.7.3:8080")));
+        assert!(rt.upsert(node_info(Id::new([0xF3; 20]), "10.0.0.1:8080")));
+        assert!(rt.upsert(node_info(Id::new([0xF4; 20]), "10.0.0.2:8080")));
+        assert!(rt.upsert(node_info(Id::new([0xF5; 20]), "10.0.0.3:8080")));
+
+        // Refreshing a node already in the table is not limited
+        assert!(rt.upsert(node_info(Id::new([0xF0; 20]), "6.6.6.1:8080")));
+
+        let limits = DiversityLimits {
+            per_bucket: KBUCKET_MAX_LENGTH,
+            per_table: 2,
+            allowlist: Vec::new(),
+        };
+        let mut rt = RoutingTable::with_limits(
+            node_info(Id::new([0x00; 20]), "1.1.1.1:8080"),
+            Difficulty::default(),
+            limits,
+            SharedReputation::default(),
+        );
+        assert!(rt.upsert(node_info(Id::new([0xF0; 20]), "6.6.6.1:8080")));
+        assert!(rt.upsert(node_info(Id::new([0xF1; 20]), "6.6.6.2:8080")));
+        assert!(!rt.upsert(node_info(Id::new([0xF2; 20]), "6.6.6.3:8080")));
+    }
+
+    #[test]
+    fn snapshot() {
+        let mut rt = RoutingTable::new(NodeInfo::with_id(
+            Id::random(),
+            "127.0.0.1:8080".to_string(),
+        ));
+        for port in 8081..8091 {
+            rt.upsert(NodeInfo::with_id(
+                Id::random(),
+                format!("127.0.0.1:{}", port),
+            ));
+        }
+
+        let nodes = rt.nodes();
+        for _ in 0..MAX_FAILURES {
+            rt.failed(&nodes[0].id);
+        }
+        rt.failed(&nodes[1].id);
+
+        let buffer = serde_json::to_vec(&Snapshot::new(&rt)).unwrap();
+        let snapshot = serde_json::from_slice::<Snapshot>(&buffer).unwrap();
+        assert_eq!(snapshot.table.node_info(), rt.node_info());
+        assert_eq!(snapshot.table.nodes(), nodes);
+        let liveness = snapshot
+            .liveness
+            .iter()
+            .find(|(id, _)| id == &nodes[1].id)
+            .map(|(_, l)| *l);
+        assert_eq!(liveness, rt.liveness(&nodes[1].id));
+        assert_eq!(liveness.map(|l| l.failures), Some(1));
+
+        // Contacts that keep failing are not restored
+        let contacts = snapshot.contacts();
+        assert_eq!(contacts.len(), nodes.len() - 1);
+        assert!(!contacts.contains(&nodes[0]));
+    }
 }
@@ -1,25 +1,179 @@
-use std::sync::Arc;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use crate::{id::Id, routing::NodeInfo};
+use crate::{
+    id::{Id, ID_SIZE},
+    ratelimit::{Dropped, RateLimiter, RateLimits},
+    reputation::{Peer, SharedReputation},
+    routing::NodeInfo,
+    session::{self, Frame, Sessions, HANDSHAKE_TIMEOUT},
+    storage::Rejection,
+};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey, SIGNATURE_LENGTH};
 use serde::{Deserialize, Serialize};
-use tokio::{net::UdpSocket, sync::mpsc::Sender, task::JoinHandle};
+use tokio::{
+    net::{self, UdpSocket},
+    sync::{mpsc::Sender, watch, Mutex},
+    task::JoinHandle,
+    time::timeout,
+};
 
 /// Maximum message size sent over the wire
 pub const MESSAGE_SIZE: usize = 2000;
 
+/// Bytes of a [`Chunk`] taken up by its message id, index and total
+pub const CHUNK_HEADER_SIZE: usize = ID_SIZE + 2 + 2;
+
+/// Bytes of a serialized [`Message`] carried by a single [`Chunk`]
+pub const CHUNK_SIZE: usize = MESSAGE_SIZE - CHUNK_HEADER_SIZE;
+
+/// Maximum number of [`Chunk`]s a [`Message`] can be split into, bounding messages to roughly 1MB
+pub const MAX_CHUNKS: usize = 512;
+
+/// Time to wait for the remaining [`Chunk`]s of a message before dropping it
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::new(5, 0);
+
+/// Most partly received messages held at once, the oldest is dropped to make room
+pub const MAX_PARTIALS: usize = 64;
+
+/// Most partly received messages held for a single source address
+pub const MAX_PARTIALS_PER_SOURCE: usize = 4;
+
+/// Bytes in front of a serialized [`Message`] holding its timestamp and signature
+pub const SEAL_HEADER_SIZE: usize = 8 + SIGNATURE_LENGTH;
+
+/// How far in the past a message's timestamp may be before it is refused
+pub const REPLAY_WINDOW: Duration = Duration::new(30, 0);
+
+/// How far ahead of our clock a message's timestamp may be, so a message cannot be kept remembered for long
+pub const MAX_CLOCK_SKEW: Duration = Duration::new(5, 0);
+
+/// Most messages a [`ReplayGuard`] remembers, well above what the rate limits let through in a window
+pub const MAX_SEEN: usize = 64 * 1024;
+
+/// Most messages a [`ReplayGuard`] remembers from a single source, so one node cannot push out everyone else's
+pub const MAX_SEEN_PER_SOURCE: usize = 4096;
+
+/// Values as base64 strings, since JSON would spell every byte out as a number and a value at
+/// [`crate::storage::StoreLimits::max_value_bytes`] would no longer fit in [`MAX_CHUNKS`]
+mod base64_bytes {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        STANDARD.decode(encoded).map_err(Error::custom)
+    }
+}
+
 /// Request message payload
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum RequestPayload {
     Ping,
-    Store { key: String, value: String },
-    FindNode { id: Id },
+    /// Store a value, kept for `ttl` or the receiving node's default when `None`. Values in a `namespace`
+    /// are checked by the receiving node's validator for it, and a key holding a namespaced value only takes
+    /// values in that namespace
+    Store {
+        key: Id,
+        #[serde(with = "base64_bytes")]
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+        namespace: Option<String>,
+        /// Copy cached along a lookup path, which the receiver never replicates
+        #[serde(default)]
+        cached: bool,
+    },
+    FindNode {
+        id: Id,
+    },
+    /// Find the value under `key`, answered like [`RequestPayload::FindNode`] when the node does not hold it
+    FindValue {
+        key: Id,
+    },
+    /// Announce the sender as a provider of the content for `key`
+    AddProvider {
+        key: Id,
+    },
+    /// Find the providers of `key`
+    GetProviders {
+        key: Id,
+    },
+}
+
+impl RequestPayload {
+    /// The [`RequestKind`] of the payload
+    pub fn kind(&self) -> RequestKind {
+        match self {
+            RequestPayload::Ping => RequestKind::Ping,
+            RequestPayload::Store { .. } => RequestKind::Store,
+            RequestPayload::FindNode { .. } => RequestKind::FindNode,
+            RequestPayload::FindValue { .. } => RequestKind::FindValue,
+            RequestPayload::AddProvider { .. } => RequestKind::AddProvider,
+            RequestPayload::GetProviders { .. } => RequestKind::GetProviders,
+        }
+    }
+}
+
+/// The variant of a [`RequestPayload`] without its fields, kept while waiting on the response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestKind {
+    Ping,
+    Store,
+    FindNode,
+    FindValue,
+    AddProvider,
+    GetProviders,
+}
+
+impl RequestKind {
+    /// Check if `response` is a kind of reply this kind of request can receive
+    pub fn expects(&self, response: &ResponsePayload) -> bool {
+        matches!(
+            (self, response),
+            (RequestKind::Ping, ResponsePayload::Pong)
+                | (RequestKind::Store, ResponsePayload::Stored)
+                | (RequestKind::Store, ResponsePayload::Rejected { .. })
+                | (RequestKind::FindNode, ResponsePayload::FindNode { .. })
+                | (RequestKind::FindValue, ResponsePayload::Value { .. })
+                | (RequestKind::FindValue, ResponsePayload::FindNode { .. })
+                | (RequestKind::AddProvider, ResponsePayload::Stored)
+                | (RequestKind::AddProvider, ResponsePayload::Rejected { .. })
+                | (RequestKind::GetProviders, ResponsePayload::Providers { .. })
+        )
+    }
 }
 
 /// Response message payload
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ResponsePayload {
     Pong,
-    FindNode { closest: Vec<NodeInfo> },
+    FindNode {
+        closest: Vec<NodeInfo>,
+    },
+    Value {
+        #[serde(with = "base64_bytes")]
+        value: Vec<u8>,
+        namespace: Option<String>,
+    },
+    Stored,
+    /// Known providers of a key along with the closest nodes to it
+    Providers {
+        providers: Vec<NodeInfo>,
+        closest: Vec<NodeInfo>,
+    },
+    /// The request was refused, such as a [`RequestPayload::Store`] or [`RequestPayload::AddProvider`] over the
+    /// node's limits
+    Rejected {
+        reason: Rejection,
+    },
 }
 
 /// Wraps request with sender details
@@ -36,6 +190,9 @@ pub struct ResponseHandle {
     pub id: Id,
     pub source: NodeInfo,
     pub request_id: Id,
+    /// Address the request was observed coming from, so the requester can learn its external address
+    #[serde(default)]
+    pub observed: Option<SocketAddr>,
     pub response: ResponsePayload,
 }
 
@@ -46,46 +203,833 @@ pub enum Message {
     Response(ResponseHandle),
 }
 
+/// A single datagram holding part of a serialized [`Message`]
+#[derive(Debug, PartialEq)]
+pub struct Chunk {
+    /// Shared by every `Chunk` of the same message
+    pub id: Id,
+    pub index: u16,
+    pub total: u16,
+    pub data: Vec<u8>,
+}
+
+impl Chunk {
+    /// Split a serialized [`Message`] into `Chunk`s, `None` if it needs more than [`MAX_CHUNKS`]
+    pub fn split(buffer: &[u8]) -> Option<Vec<Chunk>> {
+        let total = std::cmp::max(1, buffer.len().div_ceil(CHUNK_SIZE));
+        if total > MAX_CHUNKS {
+            return None;
+        }
+
+        let id = Id::random();
+        let mut chunks: Vec<Chunk> = buffer
+            .chunks(CHUNK_SIZE)
+            .enumerate()
+            .map(|(index, data)| Chunk {
+                id: id.clone(),
+                index: index as u16,
+                total: total as u16,
+                data: data.to_vec(),
+            })
+            .collect();
+
+        if chunks.is_empty() {
+            chunks.push(Chunk {
+                id,
+                index: 0,
+                total: 1,
+                data: Vec::new(),
+            });
+        }
+        Some(chunks)
+    }
+
+    /// Wire representation of the `Chunk`, the header followed by the data
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(CHUNK_HEADER_SIZE + self.data.len());
+        buffer.extend_from_slice(self.id.as_bytes());
+        buffer.extend_from_slice(&self.index.to_be_bytes());
+        buffer.extend_from_slice(&self.total.to_be_bytes());
+        buffer.extend_from_slice(&self.data);
+        buffer
+    }
+
+    /// Parse a received datagram, `None` if it is not a valid `Chunk`
+    pub fn decode(buffer: &[u8]) -> Option<Self> {
+        if buffer.len() < CHUNK_HEADER_SIZE {
+            return None;
+        }
+
+        let id = Id::new(buffer[..ID_SIZE].try_into().ok()?);
+        let index = u16::from_be_bytes(buffer[ID_SIZE..ID_SIZE + 2].try_into().ok()?);
+        let total = u16::from_be_bytes(buffer[ID_SIZE + 2..CHUNK_HEADER_SIZE].try_into().ok()?);
+        if total == 0 || total as usize > MAX_CHUNKS || index >= total {
+            return None;
+        }
+
+        Some(Self {
+            id,
+            index,
+            total,
+            data: buffer[CHUNK_HEADER_SIZE..].to_vec(),
+        })
+    }
+}
+
+/// A message that has only had some of its [`Chunk`]s arrive
+struct Partial {
+    chunks: BTreeMap<u16, Vec<u8>>,
+    total: u16,
+    started: Instant,
+}
+
+/// Collects [`Chunk`]s per sender until a whole message has arrived, holding at most [`MAX_PARTIALS`] messages
+/// and [`MAX_PARTIALS_PER_SOURCE`] from any one address
+#[derive(Default)]
+pub struct Reassembler {
+    partials: HashMap<(SocketAddr, Id), Partial>,
+    /// Partials in the order they were started, which is the order they expire in. Entries of partials that
+    /// completed are skipped when they reach the front
+    queue: VecDeque<((SocketAddr, Id), Instant)>,
+    per_source: HashMap<SocketAddr, usize>,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a [`Chunk`] received from `source`, returning the serialized message once every chunk is in
+    pub fn push(&mut self, source: SocketAddr, chunk: Chunk) -> Option<Vec<u8>> {
+        if chunk.total == 1 {
+            return Some(chunk.data);
+        }
+
+        let now = Instant::now();
+        self.expire(now);
+
+        let key = (source, chunk.id);
+        if !self.partials.contains_key(&key) {
+            if self.per_source.get(&source).copied().unwrap_or(0) >= MAX_PARTIALS_PER_SOURCE {
+                return None;
+            }
+            while self.partials.len() >= MAX_PARTIALS {
+                self.pop_oldest();
+            }
+
+            self.partials.insert(
+                key.clone(),
+                Partial {
+                    chunks: BTreeMap::new(),
+                    total: chunk.total,
+                    started: now,
+                },
+            );
+            self.queue.push_back((key.clone(), now));
+            *self.per_source.entry(source).or_default() += 1;
+        }
+
+        let partial = self
+            .partials
+            .get_mut(&key)
+            .expect("partial was just inserted");
+        // Chunks claiming a different total cannot belong to the same message
+        if partial.total != chunk.total {
+            self.remove(&key);
+            return None;
+        }
+
+        partial.chunks.entry(chunk.index).or_insert(chunk.data);
+        if partial.chunks.len() < partial.total as usize {
+            return None;
+        }
+
+        self.remove(&key)
+            .map(|p| p.chunks.into_values().flatten().collect())
+    }
+
+    /// Drop partials that have waited longer than [`REASSEMBLY_TIMEOUT`]
+    fn expire(&mut self, now: Instant) {
+        while let Some((_, started)) = self.queue.front() {
+            if now.duration_since(*started) < REASSEMBLY_TIMEOUT {
+                break;
+            }
+            self.pop_oldest();
+        }
+
+        // Completed partials leave their entries behind, drop them before they outnumber the live ones
+        if self.queue.len() > 2 * MAX_PARTIALS {
+            let partials = &self.partials;
+            self.queue
+                .retain(|(key, started)| partials.get(key).is_some_and(|p| &p.started == started));
+        }
+    }
+
+    /// Drop the partial at the front of the queue if it is still waiting
+    fn pop_oldest(&mut self) {
+        if let Some((key, started)) = self.queue.pop_front() {
+            if self
+                .partials
+                .get(&key)
+                .is_some_and(|p| p.started == started)
+            {
+                self.remove(&key);
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &(SocketAddr, Id)) -> Option<Partial> {
+        let partial = self.partials.remove(key)?;
+        if let Some(count) = self.per_source.get_mut(&key.0) {
+            *count -= 1;
+            if *count == 0 {
+                self.per_source.remove(&key.0);
+            }
+        }
+        Some(partial)
+    }
+}
+
+impl Message {
+    /// Unique id of the `Message`
+    pub fn id(&self) -> &Id {
+        match self {
+            Message::Request(request) => &request.id,
+            Message::Response(response) => &response.id,
+        }
+    }
+
+    /// The node that sent the `Message`
+    pub fn source(&self) -> &NodeInfo {
+        match self {
+            Message::Request(request) => &request.source,
+            Message::Response(response) => &response.source,
+        }
+    }
+
+    /// Bytes the signature of a sealed `Message` covers. The `destination` is signed but not sent, so a
+    /// captured message is only accepted by the node it was sent to
+    fn signed_bytes(destination: &Id, timestamp: &[u8], serialized: &[u8]) -> Vec<u8> {
+        let mut signed = destination.as_bytes().to_vec();
+        signed.extend_from_slice(timestamp);
+        signed.extend_from_slice(serialized);
+        signed
+    }
+
+    /// Serialize and sign the `Message` for `destination` with the source's key, stamped with `timestamp` in
+    /// seconds since the unix epoch. The wire format is the timestamp and signature followed by the serialized
+    /// message
+    pub fn seal(&self, signing_key: &SigningKey, destination: &Id, timestamp: u64) -> Vec<u8> {
+        let timestamp = timestamp.to_be_bytes();
+        let serialized = serde_json::to_vec(self).expect("failed to serialize message");
+        let signature = signing_key.sign(&Self::signed_bytes(destination, &timestamp, &serialized));
+
+        let mut buffer = Vec::with_capacity(SEAL_HEADER_SIZE + serialized.len());
+        buffer.extend_from_slice(&timestamp);
+        buffer.extend_from_slice(&signature.to_bytes());
+        buffer.extend_from_slice(&serialized);
+        buffer
+    }
+
+    /// Parse a `Message` sealed for `destination`, returning it with its timestamp once the signature is
+    /// checked against the public key of its source and the source's [`Id`] against that key
+    pub fn open(buffer: &[u8], destination: &Id) -> Result<(Self, u64), String> {
+        if buffer.len() < SEAL_HEADER_SIZE {
+            return Err("message is too short".to_string());
+        }
+
+        let (timestamp, rest) = buffer.split_at(8);
+        let (signature, serialized) = rest.split_at(SIGNATURE_LENGTH);
+        let message = serde_json::from_slice::<Message>(serialized).map_err(|e| e.to_string())?;
+        if !message.source().verify() {
+            return Err("id is not derived from public key".to_string());
+        }
+
+        let public_key =
+            VerifyingKey::from_bytes(&message.source().public_key).map_err(|e| e.to_string())?;
+        let signature = Signature::from_slice(signature).map_err(|e| e.to_string())?;
+        public_key
+            .verify(
+                &Self::signed_bytes(destination, timestamp, serialized),
+                &signature,
+            )
+            .map_err(|_| "bad signature".to_string())?;
+
+        let timestamp = u64::from_be_bytes(timestamp.try_into().expect("timestamp is 8 bytes"));
+        Ok((message, timestamp))
+    }
+}
+
+/// Remembers recently seen messages so a captured message cannot be sent again
+pub struct ReplayGuard {
+    /// Source and message id of each message seen within the [`REPLAY_WINDOW`]
+    seen: HashSet<(Id, Id)>,
+    /// When each seen message falls out of the window, soonest first
+    expiries: BTreeSet<(u64, Id, Id)>,
+    /// When each seen message of a source falls out of the window, soonest first
+    sources: HashMap<Id, BTreeSet<(u64, Id)>>,
+    max_seen: usize,
+    max_per_source: usize,
+}
+
+impl Default for ReplayGuard {
+    fn default() -> Self {
+        Self {
+            seen: HashSet::new(),
+            expiries: BTreeSet::new(),
+            sources: HashMap::new(),
+            max_seen: MAX_SEEN,
+            max_per_source: MAX_SEEN_PER_SOURCE,
+        }
+    }
+}
+
+impl ReplayGuard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accept a message only once, and only when its timestamp is within the [`REPLAY_WINDOW`] before `now` or
+    /// the [`MAX_CLOCK_SKEW`] after it. Beyond [`MAX_SEEN_PER_SOURCE`] messages from its source or [`MAX_SEEN`]
+    /// overall the message closest to leaving the window is forgotten. Only a source can sign more messages of
+    /// its own, so the first limit never lets anyone else's messages be replayed
+    pub fn check(&mut self, message: &Message, timestamp: u64, now: u64) -> bool {
+        let window = REPLAY_WINDOW.as_secs();
+        if timestamp.saturating_add(window) < now
+            || timestamp > now.saturating_add(MAX_CLOCK_SKEW.as_secs())
+        {
+            return false;
+        }
+
+        while let Some((expiry, source, id)) = self.expiries.first().cloned() {
+            if expiry >= now {
+                break;
+            }
+            self.forget(expiry, &source, &id);
+        }
+
+        let (source, id) = (message.source().id.clone(), message.id().clone());
+        if self.seen.contains(&(source.clone(), id.clone())) {
+            return false;
+        }
+
+        let oldest = self
+            .sources
+            .get(&source)
+            .filter(|seen| seen.len() >= self.max_per_source)
+            .and_then(|seen| seen.first().cloned());
+        if let Some((expiry, oldest)) = oldest {
+            self.forget(expiry, &source, &oldest);
+        }
+        if self.seen.len() >= self.max_seen {
+            if let Some((expiry, source, id)) = self.expiries.first().cloned() {
+                self.forget(expiry, &source, &id);
+            }
+        }
+
+        let expiry = timestamp.saturating_add(window);
+        self.expiries.insert((expiry, source.clone(), id.clone()));
+        self.sources
+            .entry(source.clone())
+            .or_default()
+            .insert((expiry, id.clone()));
+        self.seen.insert((source, id))
+    }
+
+    /// Drop a remembered message from every index
+    fn forget(&mut self, expiry: u64, source: &Id, id: &Id) {
+        self.expiries.remove(&(expiry, source.clone(), id.clone()));
+        if let Some(seen) = self.sources.get_mut(source) {
+            seen.remove(&(expiry, id.clone()));
+            if seen.is_empty() {
+                self.sources.remove(source);
+            }
+        }
+        self.seen.remove(&(source.clone(), id.clone()));
+    }
+}
+
+/// Seconds since the unix epoch
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time before unix epoch")
+        .as_secs()
+}
+
+/// Socket address of a node's `ip:port` address
+pub async fn resolve(address: &str) -> Option<SocketAddr> {
+    net::lookup_host(address).await.ok()?.next()
+}
+
+/// Send a [`Frame`] to `address`, split into as many [`Chunk`]s as it needs
+async fn transmit(socket: &UdpSocket, frame: &Frame, address: SocketAddr) {
+    let buffer = frame.encode();
+    let chunks = match Chunk::split(&buffer) {
+        Some(chunks) => chunks,
+        None => {
+            eprintln!("message of {} bytes is too large to send", buffer.len());
+            return;
+        }
+    };
+
+    for chunk in chunks {
+        if let Err(e) = socket.send_to(&chunk.encode(), address).await {
+            eprintln!("failed to send message to {}: {}", address, e);
+            return;
+        }
+    }
+}
+
 // Protocol handler for sending and recieving messages
 pub struct Rpc {
     socket: Arc<UdpSocket>,
+    /// Key every sent [`Message`] is signed with
+    signing_key: Arc<SigningKey>,
+    /// Noise key derived from `signing_key`
+    static_key: [u8; 32],
+    /// Encrypted sessions negotiated with peers
+    sessions: Arc<Mutex<Sessions>>,
+    /// Whether to negotiate a session with a peer before sending to it and refuse messages sent in the clear
+    encrypt: bool,
+    /// Peers whose messages are dropped, and the scores that get them there
+    reputation: SharedReputation,
+    /// Throttles datagrams as they arrive and requests before they reach the node
+    limiter: Arc<Mutex<RateLimiter>>,
 }
 
 impl Rpc {
-    // Create a new `Rpc` handler
-    pub fn new(socket: Arc<UdpSocket>) -> Self {
-        Self { socket }
+    // Create a new `Rpc` handler. Sessions peers start are always accepted, `encrypt` also starts them with
+    // every peer we send to and refuses to send or receive in the clear
+    pub fn new(
+        socket: Arc<UdpSocket>,
+        signing_key: Arc<SigningKey>,
+        encrypt: bool,
+        reputation: SharedReputation,
+        rate_limits: RateLimits,
+    ) -> Self {
+        Self {
+            socket,
+            static_key: session::static_private(&signing_key),
+            signing_key,
+            sessions: Arc::new(Mutex::new(Sessions::new())),
+            encrypt,
+            reputation,
+            limiter: Arc::new(Mutex::new(RateLimiter::new(rate_limits))),
+        }
+    }
+
+    /// Datagrams and requests dropped by the rate limits so far
+    pub async fn dropped(&self) -> Dropped {
+        self.limiter.lock().await.dropped()
     }
-    /// Listen for messages and send them to process
-    pub fn receive(&self, tx: Sender<Message>) -> JoinHandle<()> {
+    /// Listen for messages and send them to process along with the address they came from until `shutdown` is
+    /// signalled
+    pub fn receive(
+        &self,
+        tx: Sender<(Message, SocketAddr)>,
+        mut shutdown: watch::Receiver<bool>,
+    ) -> JoinHandle<()> {
         let socket = Arc::clone(&self.socket);
+        let sessions = Arc::clone(&self.sessions);
+        let static_key = self.static_key;
+        let encrypt = self.encrypt;
+        let id = Id::from_key(&self.signing_key.verifying_key().to_bytes());
+        let reputation = Arc::clone(&self.reputation);
+        let limiter = Arc::clone(&self.limiter);
         let receive_handle = tokio::spawn(async move {
             let mut buffer = [0u8; MESSAGE_SIZE];
+            let mut reassembler = Reassembler::new();
+            let mut replay_guard = ReplayGuard::new();
             loop {
-                let (x, _) = socket
-                    .recv_from(&mut buffer)
+                let received = tokio::select! {
+                    received = socket.recv_from(&mut buffer) => received,
+                    _ = shutdown.changed() => break,
+                };
+                // Errors such as ICMP port unreachable from a dead peer must not stop the receiver
+                let (x, source) = match received {
+                    Ok(received) => received,
+                    Err(e) => {
+                        eprintln!("failed to receive message: {}", e);
+                        continue;
+                    }
+                };
+                // Throttled before anything is decoded, so a flood costs no more than a lookup per datagram
+                if !limiter
+                    .lock()
                     .await
-                    .expect("failed to receive message");
+                    .check_source(source.ip(), Instant::now())
+                {
+                    continue;
+                }
 
-                let message = serde_json::from_slice::<Message>(&buffer[..x])
-                    .expect("failed to deserialize message");
+                // Nothing before a message is opened is authenticated and anyone can replay a signed message, so
+                // offences here are dropped rather than held against a source address that could be spoofed
+                let banned = |peer| {
+                    let reputation = reputation.lock().expect("reputation lock poisoned");
+                    reputation.is_banned(&peer)
+                };
+                if banned(Peer::address(source.ip())) {
+                    continue;
+                }
 
-                tx.send(message)
-                    .await
-                    .expect("failed to send received message to handler");
+                let chunk = match Chunk::decode(&buffer[..x]) {
+                    Some(chunk) => chunk,
+                    None => {
+                        eprintln!("received malformed chunk from {}", source);
+                        continue;
+                    }
+                };
+                let serialized = match reassembler.push(source, chunk) {
+                    Some(serialized) => serialized,
+                    None => continue,
+                };
+
+                let frame = match Frame::decode(&serialized) {
+                    Some(frame) => frame,
+                    None => {
+                        eprintln!("received malformed frame from {}", source);
+                        continue;
+                    }
+                };
+                // Handshakes are answered here, only sealed messages are passed on
+                let (sealed, remote_static) = match frame {
+                    Frame::Plain(_) if encrypt => {
+                        eprintln!("dropping unencrypted message from {}", source);
+                        continue;
+                    }
+                    Frame::Plain(sealed) => (sealed, None),
+                    Frame::Handshake { session, noise } => {
+                        let reply =
+                            sessions
+                                .lock()
+                                .await
+                                .respond(&static_key, source, session, &noise);
+                        match reply {
+                            Ok(reply) => transmit(&socket, &reply, source).await,
+                            Err(e) => eprintln!("failed handshake from {}: {}", source, e),
+                        }
+                        continue;
+                    }
+                    Frame::HandshakeReply { session, noise } => {
+                        if let Err(e) = sessions.lock().await.complete(source, session, &noise) {
+                            eprintln!("failed handshake with {}: {}", source, e);
+                        }
+                        continue;
+                    }
+                    Frame::Transport {
+                        session,
+                        nonce,
+                        ciphertext,
+                    } => {
+                        let decrypted =
+                            sessions
+                                .lock()
+                                .await
+                                .decrypt(source, session, nonce, &ciphertext);
+                        match decrypted {
+                            Ok((sealed, remote_static)) => (sealed, Some(remote_static)),
+                            Err(e) => {
+                                eprintln!("failed to decrypt message from {}: {}", source, e);
+                                continue;
+                            }
+                        }
+                    }
+                };
+
+                let (message, timestamp) = match Message::open(&sealed, &id) {
+                    Ok(opened) => opened,
+                    Err(e) => {
+                        eprintln!("dropping message from {}: {}", source, e);
+                        continue;
+                    }
+                };
+                if banned(Peer::Id(message.source().id.clone())) {
+                    continue;
+                }
+                // The session must belong to the node the message claims to be from
+                if remote_static.is_some()
+                    && remote_static != session::static_public(&message.source().public_key)
+                {
+                    eprintln!(
+                        "dropping message from {} sent on another node's session",
+                        source
+                    );
+                    continue;
+                }
+                // Responses answer our own requests so only requests count towards the global limit. It is
+                // checked first so messages over it are never remembered by the replay guard
+                if matches!(message, Message::Request(_))
+                    && !limiter.lock().await.check_global(Instant::now())
+                {
+                    continue;
+                }
+                if !replay_guard.check(&message, timestamp, unix_time()) {
+                    eprintln!("dropping replayed message from {}", source);
+                    continue;
+                }
+
+                // The handler only goes away once the node is shutting down
+                if tx.send((message, source)).await.is_err() {
+                    break;
+                }
             }
         });
 
         receive_handle
     }
 
-    /// Send a message to a node
-    pub async fn send(&self, message: &Message, node_info: &NodeInfo) {
+    /// Send a message to a node at an `address` resolved by the caller, encrypted when there is a session with it
+    pub async fn send(&self, message: &Message, node_info: &NodeInfo, address: SocketAddr) {
         println!("sending message");
-        let buffer = serde_json::to_vec(message).expect("failed to serialize message");
-        self.socket
-            .send_to(&buffer, &node_info.address)
-            .await
-            .expect("failed to send message");
+        let sealed = message.seal(&self.signing_key, &node_info.id, unix_time());
+        // Only a session negotiated with the node's own key may carry the message, whoever else is at the address
+        let remote_static = session::static_public(&node_info.public_key);
+        let encrypt = |sessions: &mut Sessions| {
+            remote_static.and_then(|key| sessions.encrypt(address, &key, &sealed))
+        };
+        let mut frame = encrypt(&mut *self.sessions.lock().await);
+        if frame.is_none() && self.encrypt {
+            if !self.handshake(node_info, address).await {
+                eprintln!("failed to establish a session with {}", address);
+                return;
+            }
+            frame = encrypt(&mut *self.sessions.lock().await);
+        }
+
+        match frame {
+            Some(frame) => transmit(&self.socket, &frame, address).await,
+            None if self.encrypt => eprintln!("no session to send to {} with", address),
+            None => transmit(&self.socket, &Frame::Plain(sealed), address).await,
+        }
+    }
+
+    /// Negotiate a session with a node, `false` if it did not answer in time
+    async fn handshake(&self, node_info: &NodeInfo, address: SocketAddr) -> bool {
+        let remote_static = match session::static_public(&node_info.public_key) {
+            Some(remote_static) => remote_static,
+            None => return false,
+        };
+        let initiated =
+            self.sessions
+                .lock()
+                .await
+                .initiate(&self.static_key, address, &remote_static);
+        let (frame, done) = match initiated {
+            Ok(initiated) => initiated,
+            Err(e) => {
+                eprintln!("failed to start handshake with {}: {}", address, e);
+                return false;
+            }
+        };
+
+        transmit(&self.socket, &frame, address).await;
+        matches!(timeout(HANDSHAKE_TIMEOUT, done).await, Ok(Ok(())))
+    }
+
+    /// Drop any sessions with the node at `address` so the next message negotiates a new one
+    pub async fn forget(&self, address: &SocketAddr) {
+        self.sessions.lock().await.forget(address);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::StoreLimits;
+    use rand::rngs::OsRng;
+
+    fn ping(signing_key: &SigningKey) -> Message {
+        let source = NodeInfo::new(&signing_key.verifying_key(), "localhost:8080".to_string());
+        Message::Request(RequestHandle {
+            id: Id::random(),
+            source,
+            request: RequestPayload::Ping,
+        })
+    }
+
+    #[test]
+    fn sealing() {
+        let signing_key = SigningKey::generate(&mut OsRng);
+        let destination = Id::random();
+        let buffer = ping(&signing_key).seal(&signing_key, &destination, 42);
+        let (message, timestamp) = Message::open(&buffer, &destination).unwrap();
+        assert_eq!(timestamp, 42);
+        assert!(matches!(message, Message::Request(_)));
+
+        let mut tampered = buffer.clone();
+        tampered[0] ^= 1;
+        assert!(Message::open(&tampered, &destination).is_err());
+
+        // Replayed to a node other than the one it was sent to
+        assert!(Message::open(&buffer, &Id::random()).is_err());
+
+        // Signed by a key other than the one the source claims
+        let forged = ping(&signing_key).seal(&SigningKey::generate(&mut OsRng), &destination, 42);
+        assert!(Message::open(&forged, &destination).is_err());
+    }
+
+    #[test]
+    fn largest_value() {
+        let signing_key = SigningKey::generate(&mut OsRng);
+        let destination = Id::random();
+        let value = vec![u8::MAX; StoreLimits::default().max_value_bytes];
+        let message = Message::Request(RequestHandle {
+            id: Id::random(),
+            source: NodeInfo::new(&signing_key.verifying_key(), "localhost:8080".to_string()),
+            request: RequestPayload::Store {
+                key: Id::random(),
+                value: value.clone(),
+                ttl: None,
+                namespace: None,
+                cached: false,
+            },
+        });
+        let sealed = message.seal(&signing_key, &destination, 42);
+        let chunks = Chunk::split(&Frame::Plain(sealed.clone()).encode()).unwrap();
+        assert!(chunks.len() <= MAX_CHUNKS);
+
+        match Message::open(&sealed, &destination).unwrap().0 {
+            Message::Request(RequestHandle {
+                request: RequestPayload::Store { value: opened, .. },
+                ..
+            }) => assert_eq!(opened, value),
+            _ => panic!("expected store request"),
+        }
+    }
+
+    #[test]
+    fn replay() {
+        let message = ping(&SigningKey::generate(&mut OsRng));
+        let (window, skew) = (REPLAY_WINDOW.as_secs(), MAX_CLOCK_SKEW.as_secs());
+        let mut replay_guard = ReplayGuard::new();
+        assert!(replay_guard.check(&message, 1000, 1000));
+        assert!(!replay_guard.check(&message, 1000, 1001));
+        assert!(!replay_guard.check(&message, 1000, 1001 + window));
+
+        let message = ping(&SigningKey::generate(&mut OsRng));
+        assert!(!replay_guard.check(&message, 1000 + skew + 1, 1000));
+        assert!(replay_guard.check(&message, 1000 + skew, 1000));
+
+        // Messages are forgotten once they fall out of the window
+        let message = ping(&SigningKey::generate(&mut OsRng));
+        assert!(replay_guard.check(&message, 1000 + window, 1001 + window + skew));
+        assert_eq!(replay_guard.seen.len(), 1);
+        assert_eq!(replay_guard.sources.len(), 1);
+    }
+
+    #[test]
+    fn replay_limits() {
+        let mut replay_guard = ReplayGuard {
+            max_seen: 8,
+            max_per_source: 4,
+            ..ReplayGuard::new()
+        };
+        let flooder = SigningKey::generate(&mut OsRng);
+        let first = ping(&flooder);
+        assert!(replay_guard.check(&first, 1000, 1000));
+        let other = ping(&SigningKey::generate(&mut OsRng));
+        assert!(replay_guard.check(&other, 1000, 1000));
+
+        // A source over its limit only pushes out its own oldest messages
+        for timestamp in 1001..1010 {
+            assert!(replay_guard.check(&ping(&flooder), timestamp, 1010));
+        }
+        assert_eq!(replay_guard.sources[&first.source().id].len(), 4);
+        assert!(replay_guard.check(&first, 1000, 1000));
+        assert!(!replay_guard.check(&other, 1000, 1000));
+
+        // Once every source together is over the limit the message closest to leaving the window goes
+        for _ in 0..8 {
+            let key = SigningKey::generate(&mut OsRng);
+            assert!(replay_guard.check(&ping(&key), 1005, 1000));
+        }
+        assert_eq!(replay_guard.seen.len(), 8);
+        assert!(replay_guard.check(&other, 1000, 1000));
+    }
+
+    #[test]
+    fn expects() {
+        let find_value = RequestPayload::FindValue { key: Id::random() }.kind();
+        assert!(find_value.expects(&ResponsePayload::FindNode {
+            closest: Vec::new()
+        }));
+        assert!(find_value.expects(&ResponsePayload::Value {
+            value: Vec::new(),
+            namespace: None
+        }));
+        assert!(!find_value.expects(&ResponsePayload::Pong));
+        assert!(RequestKind::Ping.expects(&ResponsePayload::Pong));
+        assert!(!RequestKind::Ping.expects(&ResponsePayload::Stored));
+    }
+
+    #[test]
+    fn chunk_encoding() {
+        let chunk = Chunk {
+            id: Id::random(),
+            index: 3,
+            total: 7,
+            data: vec![1, 2, 3],
+        };
+        let buffer = chunk.encode();
+        assert_eq!(buffer.len(), CHUNK_HEADER_SIZE + 3);
+        assert_eq!(Chunk::decode(&buffer), Some(chunk));
+        assert_eq!(Chunk::decode(&buffer[..CHUNK_HEADER_SIZE - 1]), None);
+    }
+
+    #[test]
+    fn reassembly() {
+        let source: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let buffer: Vec<u8> = (0..5 * CHUNK_SIZE + 10).map(|i| i as u8).collect();
+        let mut chunks = Chunk::split(&buffer).unwrap();
+        assert_eq!(chunks.len(), 6);
+        assert!(chunks.iter().all(|c| c.encode().len() <= MESSAGE_SIZE));
+
+        let mut reassembler = Reassembler::new();
+        let last = chunks.remove(2);
+        for chunk in chunks.into_iter().rev() {
+            assert_eq!(reassembler.push(source, chunk), None);
+        }
+        assert_eq!(reassembler.push(source, last), Some(buffer));
+
+        assert!(Chunk::split(&vec![0; MAX_CHUNKS * CHUNK_SIZE + 1]).is_none());
+    }
+
+    #[test]
+    fn reassembly_limits() {
+        let first = |port: u16| {
+            let source: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+            let chunk = Chunk {
+                id: Id::random(),
+                index: 0,
+                total: 2,
+                data: vec![0],
+            };
+            (source, chunk)
+        };
+        let mut reassembler = Reassembler::new();
+
+        let (source, chunk) = first(8080);
+        for _ in 0..MAX_PARTIALS_PER_SOURCE + 1 {
+            let (_, chunk) = first(8080);
+            reassembler.push(source, chunk);
+        }
+        assert_eq!(reassembler.partials.len(), MAX_PARTIALS_PER_SOURCE);
+        assert_eq!(reassembler.per_source[&source], MAX_PARTIALS_PER_SOURCE);
+
+        // A full reassembler makes room by dropping the oldest partial
+        let oldest = reassembler.queue.front().unwrap().0.clone();
+        for port in 0..MAX_PARTIALS as u16 {
+            let (source, chunk) = first(9000 + port);
+            reassembler.push(source, chunk);
+        }
+        assert_eq!(reassembler.partials.len(), MAX_PARTIALS);
+        assert!(!reassembler.partials.contains_key(&oldest));
+
+        // Partials dropped from the source make room for it again
+        assert!(!reassembler.per_source.contains_key(&source));
+        let id = chunk.id.clone();
+        reassembler.push(source, chunk);
+        assert!(reassembler.partials.contains_key(&(source, id)));
     }
 }
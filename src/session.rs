@@ -0,0 +1,596 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use ed25519_dalek::{SigningKey, VerifyingKey, PUBLIC_KEY_LENGTH};
+use snow::{Builder, HandshakeState, StatelessTransportState};
+use tokio::sync::oneshot;
+
+/// Noise handshake pattern used for sessions. IK as the initiator already knows the responder's static key from
+/// its [`crate::routing::NodeInfo`], so the handshake takes a single round trip
+pub const NOISE_PARAMS: &str = "Noise_IK_25519_ChaChaPoly_BLAKE2s";
+
+/// Largest message Noise can encrypt at once
+pub const NOISE_MESSAGE_SIZE: usize = 65535;
+
+/// Bytes added to every encrypted block by its authentication tag
+const TAG_SIZE: usize = 16;
+
+/// Largest plaintext encrypted as one block, longer plaintexts take several
+const BLOCK_SIZE: usize = NOISE_MESSAGE_SIZE - TAG_SIZE;
+
+/// Time to wait for a peer to answer a handshake
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::new(1, 0);
+
+/// Time before a session is no longer used to send on and has to be negotiated again
+pub const SESSION_LIFETIME: Duration = Duration::new(60 * 60, 0);
+
+/// Most peers sessions are kept with, the least recently established are dropped beyond it
+pub const MAX_PEERS: usize = 4096;
+
+/// X25519 private key for Noise, derived from the node's identity key
+pub fn static_private(signing_key: &SigningKey) -> [u8; 32] {
+    signing_key.to_scalar_bytes()
+}
+
+/// X25519 public key for Noise of the node with an Ed25519 `public_key`
+pub fn static_public(public_key: &[u8; PUBLIC_KEY_LENGTH]) -> Option<[u8; 32]> {
+    VerifyingKey::from_bytes(public_key)
+        .ok()
+        .map(|key| key.to_montgomery().to_bytes())
+}
+
+/// What is carried by a datagram before it is split into [`crate::rpc::Chunk`]s
+#[derive(Debug, PartialEq)]
+pub enum Frame {
+    /// A sealed [`crate::rpc::Message`] sent in the clear
+    Plain(Vec<u8>),
+    /// First Noise message of a session started by the sender
+    Handshake { session: u64, noise: Vec<u8> },
+    /// Noise message answering a [`Frame::Handshake`]
+    HandshakeReply { session: u64, noise: Vec<u8> },
+    /// A sealed [`crate::rpc::Message`] encrypted for an established session, starting at `nonce`
+    Transport {
+        session: u64,
+        nonce: u64,
+        ciphertext: Vec<u8>,
+    },
+}
+
+impl Frame {
+    /// Wire representation of the `Frame`, a tag byte followed by its fields
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        match self {
+            Frame::Plain(sealed) => {
+                buffer.push(0);
+                buffer.extend_from_slice(sealed);
+            }
+            Frame::Handshake { session, noise } => {
+                buffer.push(1);
+                buffer.extend_from_slice(&session.to_be_bytes());
+                buffer.extend_from_slice(noise);
+            }
+            Frame::HandshakeReply { session, noise } => {
+                buffer.push(2);
+                buffer.extend_from_slice(&session.to_be_bytes());
+                buffer.extend_from_slice(noise);
+            }
+            Frame::Transport {
+                session,
+                nonce,
+                ciphertext,
+            } => {
+                buffer.push(3);
+                buffer.extend_from_slice(&session.to_be_bytes());
+                buffer.extend_from_slice(&nonce.to_be_bytes());
+                buffer.extend_from_slice(ciphertext);
+            }
+        }
+        buffer
+    }
+
+    /// Parse a reassembled datagram, `None` if it is not a valid `Frame`
+    pub fn decode(buffer: &[u8]) -> Option<Self> {
+        let (tag, rest) = buffer.split_first()?;
+        if *tag == 0 {
+            return Some(Frame::Plain(rest.to_vec()));
+        }
+
+        let session = u64::from_be_bytes(rest.get(..8)?.try_into().ok()?);
+        let rest = &rest[8..];
+        match tag {
+            1 => Some(Frame::Handshake {
+                session,
+                noise: rest.to_vec(),
+            }),
+            2 => Some(Frame::HandshakeReply {
+                session,
+                noise: rest.to_vec(),
+            }),
+            3 => Some(Frame::Transport {
+                session,
+                nonce: u64::from_be_bytes(rest.get(..8)?.try_into().ok()?),
+                ciphertext: rest[8..].to_vec(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// An established encrypted session with a peer
+pub struct Session {
+    transport: StatelessTransportState,
+    /// Static key the peer authenticated with during the handshake
+    remote_static: [u8; 32],
+    /// Next unused nonce for encrypting
+    nonce: u64,
+    established: Instant,
+}
+
+impl Session {
+    fn new(handshake: HandshakeState) -> Result<Self, String> {
+        let remote_static = handshake
+            .get_remote_static()
+            .and_then(|key| key.try_into().ok())
+            .ok_or("peer sent no static key")?;
+        Ok(Self {
+            transport: handshake
+                .into_stateless_transport_mode()
+                .map_err(|e| e.to_string())?,
+            remote_static,
+            nonce: 0,
+            established: Instant::now(),
+        })
+    }
+
+    /// Encrypt `plaintext` in blocks of at most [`BLOCK_SIZE`], returning the nonce of the first block
+    fn encrypt(&mut self, plaintext: &[u8]) -> Result<(u64, Vec<u8>), String> {
+        let start = self.nonce;
+        let mut ciphertext = Vec::with_capacity(plaintext.len() + TAG_SIZE);
+        let mut block = vec![0; NOISE_MESSAGE_SIZE];
+        let blocks: Vec<&[u8]> = match plaintext.is_empty() {
+            true => vec![&[]],
+            false => plaintext.chunks(BLOCK_SIZE).collect(),
+        };
+
+        for data in blocks {
+            let x = self
+                .transport
+                .write_message(self.nonce, data, &mut block)
+                .map_err(|e| e.to_string())?;
+            ciphertext.extend_from_slice(&block[..x]);
+            self.nonce += 1;
+        }
+        Ok((start, ciphertext))
+    }
+
+    /// Decrypt blocks encrypted by the peer starting at `nonce`
+    fn decrypt(&self, nonce: u64, ciphertext: &[u8]) -> Result<Vec<u8>, String> {
+        let mut plaintext = Vec::with_capacity(ciphertext.len());
+        let mut block = vec![0; NOISE_MESSAGE_SIZE];
+        for (i, data) in ciphertext.chunks(NOISE_MESSAGE_SIZE).enumerate() {
+            let x = self
+                .transport
+                .read_message(nonce + i as u64, data, &mut block)
+                .map_err(|e| e.to_string())?;
+            plaintext.extend_from_slice(&block[..x]);
+        }
+        Ok(plaintext)
+    }
+}
+
+/// Sessions with a single peer, the one it started with us and the one we started with it
+#[derive(Default)]
+struct Peer {
+    inbound: Option<(u64, Session)>,
+    outbound: Option<(u64, Session)>,
+}
+
+impl Peer {
+    /// Sessions with the peer that have not outlived [`SESSION_LIFETIME`]
+    fn live(&self) -> impl Iterator<Item = &Session> {
+        [&self.inbound, &self.outbound]
+            .into_iter()
+            .flatten()
+            .map(|(_, session)| session)
+            .filter(|session| session.established.elapsed() < SESSION_LIFETIME)
+    }
+
+    /// When the newest session with the peer was established
+    fn established(&self) -> Option<Instant> {
+        [&self.inbound, &self.outbound]
+            .into_iter()
+            .flatten()
+            .map(|(_, session)| session.established)
+            .max()
+    }
+}
+
+/// A handshake we started, waiting on the peer's reply
+struct Pending {
+    handshake: HandshakeState,
+    started: Instant,
+    done: oneshot::Sender<()>,
+}
+
+/// Encrypted sessions negotiated with peers, keyed by their address
+pub struct Sessions {
+    pending: HashMap<(SocketAddr, u64), Pending>,
+    peers: HashMap<SocketAddr, Peer>,
+    max_peers: usize,
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Self {
+            pending: HashMap::new(),
+            peers: HashMap::new(),
+            max_peers: MAX_PEERS,
+        }
+    }
+}
+
+impl Sessions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start a handshake with the peer at `address`, returning the [`Frame`] to send it and a receiver that
+    /// resolves once the peer has answered
+    pub fn initiate(
+        &mut self,
+        local_private: &[u8; 32],
+        address: SocketAddr,
+        remote_public: &[u8; 32],
+    ) -> Result<(Frame, oneshot::Receiver<()>), String> {
+        let mut handshake = Builder::new(NOISE_PARAMS.parse().expect("invalid noise params"))
+            .local_private_key(local_private)
+            .remote_public_key(remote_public)
+            .build_initiator()
+            .map_err(|e| e.to_string())?;
+        let mut noise = vec![0; NOISE_MESSAGE_SIZE];
+        let x = handshake
+            .write_message(&[], &mut noise)
+            .map_err(|e| e.to_string())?;
+        noise.truncate(x);
+
+        let now = Instant::now();
+        self.pending
+            .retain(|_, p| now.duration_since(p.started) < HANDSHAKE_TIMEOUT);
+
+        let session = rand::random();
+        let (done, rx) = oneshot::channel();
+        self.pending.insert(
+            (address, session),
+            Pending {
+                handshake,
+                started: now,
+                done,
+            },
+        );
+        Ok((Frame::Handshake { session, noise }, rx))
+    }
+
+    /// Answer a handshake started by the peer at `address`, returning the [`Frame`] to reply with. The new
+    /// session replaces any the peer started before, but only if it authenticated with the same static key as the
+    /// peer's live sessions so a handshake from a spoofed address cannot take them over
+    pub fn respond(
+        &mut self,
+        local_private: &[u8; 32],
+        address: SocketAddr,
+        session: u64,
+        noise: &[u8],
+    ) -> Result<Frame, String> {
+        let mut handshake = Builder::new(NOISE_PARAMS.parse().expect("invalid noise params"))
+            .local_private_key(local_private)
+            .build_responder()
+            .map_err(|e| e.to_string())?;
+        let mut buffer = vec![0; NOISE_MESSAGE_SIZE];
+        handshake
+            .read_message(noise, &mut buffer)
+            .map_err(|e| e.to_string())?;
+        let x = handshake
+            .write_message(&[], &mut buffer)
+            .map_err(|e| e.to_string())?;
+        buffer.truncate(x);
+
+        let state = Session::new(handshake)?;
+        if let Some(peer) = self.peers.get(&address) {
+            if peer
+                .live()
+                .any(|known| known.remote_static != state.remote_static)
+            {
+                return Err("handshake with a different static key than the peer's".to_string());
+            }
+        }
+
+        self.make_room(&address);
+        let peer = self.peers.entry(address).or_default();
+        peer.inbound = Some((session, state));
+        Ok(Frame::HandshakeReply {
+            session,
+            noise: buffer,
+        })
+    }
+
+    /// Finish a handshake we started once the peer at `address` has replied
+    pub fn complete(
+        &mut self,
+        address: SocketAddr,
+        session: u64,
+        noise: &[u8],
+    ) -> Result<(), String> {
+        let mut pending = self
+            .pending
+            .remove(&(address, session))
+            .ok_or("reply to unknown handshake")?;
+        let mut buffer = vec![0; NOISE_MESSAGE_SIZE];
+        pending
+            .handshake
+            .read_message(noise, &mut buffer)
+            .map_err(|e| e.to_string())?;
+
+        let state = Session::new(pending.handshake)?;
+        self.make_room(&address);
+        let peer = self.peers.entry(address).or_default();
+        peer.outbound = Some((session, state));
+        let _ = pending.done.send(());
+        Ok(())
+    }
+
+    /// Encrypt a sealed message for the peer at `address`, preferring the session we started. Only sessions the
+    /// peer authenticated in with `remote_static`, the key of the node the message is for, are used, so a
+    /// session a different node negotiated from that address never carries it. `None` when there is no usable
+    /// session
+    pub fn encrypt(
+        &mut self,
+        address: SocketAddr,
+        remote_static: &[u8; 32],
+        plaintext: &[u8],
+    ) -> Option<Frame> {
+        let peer = self.peers.get_mut(&address)?;
+        for direction in [&mut peer.outbound, &mut peer.inbound] {
+            if let Some((_, ref session)) = direction {
+                if session.established.elapsed() >= SESSION_LIFETIME {
+                    *direction = None;
+                }
+            }
+        }
+
+        let (session, state) = [peer.outbound.as_mut(), peer.inbound.as_mut()]
+            .into_iter()
+            .flatten()
+            .find(|(_, state)| state.remote_static == *remote_static)?;
+        match state.encrypt(plaintext) {
+            Ok((nonce, ciphertext)) => Some(Frame::Transport {
+                session: *session,
+                nonce,
+                ciphertext,
+            }),
+            Err(e) => {
+                eprintln!("failed to encrypt message for {}: {}", address, e);
+                None
+            }
+        }
+    }
+
+    /// Decrypt a [`Frame::Transport`] from the peer at `address`, returning the plaintext and the static key the
+    /// peer authenticated with. Sessions past [`SESSION_LIFETIME`] are refused once the peer has had a
+    /// [`HANDSHAKE_TIMEOUT`] to notice, as it may have established the session that much later than us
+    pub fn decrypt(
+        &self,
+        address: SocketAddr,
+        session: u64,
+        nonce: u64,
+        ciphertext: &[u8],
+    ) -> Result<(Vec<u8>, [u8; 32]), String> {
+        let peer = self.peers.get(&address).ok_or("no session with peer")?;
+        let (_, state) = [&peer.outbound, &peer.inbound]
+            .into_iter()
+            .flatten()
+            .find(|(id, _)| *id == session)
+            .ok_or("unknown session")?;
+        if state.established.elapsed() >= SESSION_LIFETIME + HANDSHAKE_TIMEOUT {
+            return Err("expired session".to_string());
+        }
+        Ok((state.decrypt(nonce, ciphertext)?, state.remote_static))
+    }
+
+    /// Drop the peer whose newest session is the oldest when there is no room for one at `address`
+    fn make_room(&mut self, address: &SocketAddr) {
+        if self.peers.len() < self.max_peers || self.peers.contains_key(address) {
+            return;
+        }
+        let oldest = self
+            .peers
+            .iter()
+            .min_by_key(|(_, peer)| peer.established())
+            .map(|(address, _)| *address);
+        if let Some(oldest) = oldest {
+            self.peers.remove(&oldest);
+        }
+    }
+
+    /// Drop every session with the peer at `address`, such as when it stops answering after a restart
+    pub fn forget(&mut self, address: &SocketAddr) {
+        self.peers.remove(address);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::rngs::OsRng;
+
+    #[test]
+    fn frame_encoding() {
+        let frames = [
+            Frame::Plain(vec![1, 2, 3]),
+            Frame::Handshake {
+                session: 7,
+                noise: vec![4, 5],
+            },
+            Frame::Transport {
+                session: 7,
+                nonce: 9,
+                ciphertext: Vec::new(),
+            },
+        ];
+        for frame in frames {
+            assert_eq!(Frame::decode(&frame.encode()), Some(frame));
+        }
+        assert_eq!(Frame::decode(&[3, 0, 0]), None);
+        assert_eq!(Frame::decode(&[]), None);
+    }
+
+    #[test]
+    fn handshake() {
+        let (a, b) = (
+            SigningKey::generate(&mut OsRng),
+            SigningKey::generate(&mut OsRng),
+        );
+        let a_address: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let b_address: SocketAddr = "127.0.0.1:8081".parse().unwrap();
+        let (mut a_sessions, mut b_sessions) = (Sessions::new(), Sessions::new());
+        let a_public = static_public(&a.verifying_key().to_bytes()).unwrap();
+        let b_public = static_public(&b.verifying_key().to_bytes()).unwrap();
+
+        let (frame, mut done) = a_sessions
+            .initiate(&static_private(&a), b_address, &b_public)
+            .unwrap();
+        let (session, noise) = match frame {
+            Frame::Handshake { session, noise } => (session, noise),
+            _ => panic!("expected handshake"),
+        };
+        assert!(a_sessions.encrypt(b_address, &b_public, b"hello").is_none());
+
+        let reply = b_sessions
+            .respond(&static_private(&b), a_address, session, &noise)
+            .unwrap();
+        let noise = match reply {
+            Frame::HandshakeReply { noise, .. } => noise,
+            _ => panic!("expected handshake reply"),
+        };
+        a_sessions.complete(b_address, session, &noise).unwrap();
+        assert!(done.try_recv().is_ok());
+
+        let large: Vec<u8> = (0..2 * BLOCK_SIZE + 5).map(|i| i as u8).collect();
+        for plaintext in [b"hello".to_vec(), large] {
+            let (session, nonce, ciphertext) =
+                match a_sessions.encrypt(b_address, &b_public, &plaintext) {
+                    Some(Frame::Transport {
+                        session,
+                        nonce,
+                        ciphertext,
+                    }) => (session, nonce, ciphertext),
+                    _ => panic!("expected transport"),
+                };
+            assert_ne!(ciphertext[..5], plaintext[..5]);
+            let (decrypted, remote_static) = b_sessions
+                .decrypt(a_address, session, nonce, &ciphertext)
+                .unwrap();
+            assert_eq!(decrypted, plaintext);
+            assert_eq!(remote_static, a_public);
+        }
+
+        // The responder answers on the session the initiator started
+        match b_sessions.encrypt(a_address, &a_public, b"pong") {
+            Some(Frame::Transport {
+                session,
+                nonce,
+                ciphertext,
+            }) => {
+                let (decrypted, _) = a_sessions
+                    .decrypt(b_address, session, nonce, &ciphertext)
+                    .unwrap();
+                assert_eq!(decrypted, b"pong");
+            }
+            _ => panic!("expected transport"),
+        }
+
+        // Sessions are only used for the node whose key they were negotiated with
+        let other = static_public(&SigningKey::generate(&mut OsRng).verifying_key().to_bytes());
+        assert!(b_sessions
+            .encrypt(a_address, &other.unwrap(), b"pong")
+            .is_none());
+
+        // Nor once they are past their lifetime, in either direction
+        if let Some(expired) = Instant::now().checked_sub(SESSION_LIFETIME) {
+            let peer = b_sessions.peers.get_mut(&a_address).unwrap();
+            peer.inbound.as_mut().unwrap().1.established = expired;
+            assert!(b_sessions.encrypt(a_address, &a_public, b"pong").is_none());
+            assert!(b_sessions.peers[&a_address].inbound.is_none());
+        }
+    }
+
+    /// Have a fresh [`Sessions`] for `signing_key` start a handshake with `responder` and let it answer as if
+    /// the handshake came from `address`
+    fn handshake_from(
+        signing_key: &SigningKey,
+        address: SocketAddr,
+        responder_key: &SigningKey,
+        responder: &mut Sessions,
+    ) -> Result<Frame, String> {
+        let public = static_public(&responder_key.verifying_key().to_bytes()).unwrap();
+        let (frame, _) = Sessions::new()
+            .initiate(&static_private(signing_key), address, &public)
+            .unwrap();
+        match frame {
+            Frame::Handshake { session, noise } => {
+                responder.respond(&static_private(responder_key), address, session, &noise)
+            }
+            _ => panic!("expected handshake"),
+        }
+    }
+
+    #[test]
+    fn spoofed_handshake() {
+        let (a, b, mallory) = (
+            SigningKey::generate(&mut OsRng),
+            SigningKey::generate(&mut OsRng),
+            SigningKey::generate(&mut OsRng),
+        );
+        let a_address: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let mut b_sessions = Sessions::new();
+
+        handshake_from(&a, a_address, &b, &mut b_sessions).unwrap();
+        let remote_static = |sessions: &Sessions| {
+            sessions.peers[&a_address]
+                .inbound
+                .as_ref()
+                .map(|(_, session)| session.remote_static)
+        };
+        let a_static = static_public(&a.verifying_key().to_bytes());
+        assert_eq!(remote_static(&b_sessions), a_static);
+
+        // Another key cannot take over the session of the peer at that address
+        assert!(handshake_from(&mallory, a_address, &b, &mut b_sessions).is_err());
+        assert_eq!(remote_static(&b_sessions), a_static);
+
+        // The same peer may renegotiate
+        handshake_from(&a, a_address, &b, &mut b_sessions).unwrap();
+        assert_eq!(remote_static(&b_sessions), a_static);
+    }
+
+    #[test]
+    fn peer_limit() {
+        let (a, b) = (
+            SigningKey::generate(&mut OsRng),
+            SigningKey::generate(&mut OsRng),
+        );
+        let mut b_sessions = Sessions {
+            max_peers: 4,
+            ..Sessions::new()
+        };
+        let address = |port: u16| SocketAddr::from(([127, 0, 0, 1], port));
+        for port in 0..5 {
+            handshake_from(&a, address(port), &b, &mut b_sessions).unwrap();
+        }
+        assert_eq!(b_sessions.peers.len(), 4);
+        assert!(!b_sessions.peers.contains_key(&address(0)));
+        assert!(b_sessions.peers.contains_key(&address(4)));
+    }
+}
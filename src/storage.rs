@@ -1,75 +1,826 @@
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+    fs::{self, File, OpenOptions},
     hash::Hash,
-    sync::Arc,
-    time::{Duration, Instant},
+    io::{self, BufRead, BufReader, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
 };
 
-//// 24 hour duration before a key is removed
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{id::Id, kbucket::KBUCKET_MAX_LENGTH, validation::RecordValidator};
+
+/// 24 hour duration before a key is removed when the publisher does not ask for a ttl
 pub const STALE_DURATION: Duration = Duration::new(24 * 60 * 60, 0);
 
+/// Minimum number of log entries before a [`FileBackend`] considers compacting
+pub const COMPACTION_THRESHOLD: usize = 1024;
+
+/// Scale a record's `ttl` down by the number of nodes `closer` to its key than we are. Records on the k
+/// closest nodes keep their full ttl, after that it halves for every further k nodes in between
+pub fn scaled_ttl(ttl: Duration, closer: usize) -> Duration {
+    let halvings = (closer / KBUCKET_MAX_LENGTH).min(31) as u32;
+    ttl / 2u32.pow(halvings)
+}
+
+/// Bounds on what a [`Store`] of byte values admits
+#[derive(Debug, Clone)]
+pub struct StoreLimits {
+    /// Largest single value
+    pub max_value_bytes: usize,
+    /// Largest sum of all values
+    pub max_total_bytes: usize,
+    /// Largest number of records
+    pub max_records: usize,
+    /// Largest sum of values stored by a single source IP address
+    pub max_source_bytes: usize,
+}
+
+impl Default for StoreLimits {
+    fn default() -> Self {
+        Self {
+            max_value_bytes: 256 * 1024,
+            max_total_bytes: 64 * 1024 * 1024,
+            max_records: 64 * 1024,
+            max_source_bytes: 4 * 1024 * 1024,
+        }
+    }
+}
+
+/// Why a [`Store`] refused a record
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    /// The value is over [`StoreLimits::max_value_bytes`]
+    ValueTooLarge,
+    /// The store is at [`StoreLimits::max_total_bytes`] or [`StoreLimits::max_records`], or the providers at
+    /// their [`crate::providers::ProviderLimits`]
+    StoreFull,
+    /// The source IP address is at [`StoreLimits::max_source_bytes`] or
+    /// [`crate::providers::ProviderLimits::max_source_providers`]
+    QuotaExceeded,
+    /// The namespace's [`RecordValidator`] refused the value, or the namespace has none
+    Invalid { reason: String },
+    /// The namespace's [`RecordValidator`] prefers the value already stored
+    Superseded,
+}
+
+impl Display for Rejection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::ValueTooLarge => f.write_str("value too large"),
+            Rejection::StoreFull => f.write_str("store full"),
+            Rejection::QuotaExceeded => f.write_str("source quota exceeded"),
+            Rejection::Invalid { reason } => write!(f, "invalid record: {}", reason),
+            Rejection::Superseded => f.write_str("superseded by the stored record"),
+        }
+    }
+}
+
+/// A value held by a [`Store`] along with when it was upserted and when it expires
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Record<V> {
+    pub value: V,
+    pub timestamp: SystemTime,
+    pub expires: SystemTime,
+    /// The node that sent us the record, `None` when it was stored locally
+    #[serde(default)]
+    pub source: Option<Id>,
+    /// IP address the record was sent from, which its bytes count towards [`StoreLimits::max_source_bytes`] of
+    #[serde(default)]
+    pub origin: Option<IpAddr>,
+    /// Namespace whose [`RecordValidator`] the record was checked with, `None` for plain records
+    #[serde(default)]
+    pub namespace: Option<String>,
+    /// Copy cached along a lookup path rather than stored by its publisher or a replica holder
+    #[serde(default)]
+    pub cached: bool,
+}
+
+impl<V> Record<V> {
+    /// Create a `Record` upserted now that lives for `ttl`
+    pub fn new(value: V, ttl: Duration) -> Self {
+        let timestamp = SystemTime::now();
+        Self {
+            value,
+            timestamp,
+            expires: timestamp + ttl,
+            source: None,
+            origin: None,
+            namespace: None,
+            cached: false,
+        }
+    }
+
+    /// How much longer the `Record` lives for at `now`
+    pub fn ttl(&self, now: SystemTime) -> Duration {
+        self.expires.duration_since(now).unwrap_or_default()
+    }
+
+    /// Whether the `Record` has outlived its ttl at `now`
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        now >= self.expires
+    }
+}
+
+/// Where a [`Store`] keeps its records
+pub trait StorageBackend<K, V>: Send + Sync {
+    /// Insert or replace the [`Record`] under `k`
+    fn put(&mut self, k: K, record: Record<V>) -> io::Result<()>;
+
+    /// Fetch the [`Record`] under `k`
+    fn get(&self, k: &K) -> Option<Record<V>>;
+
+    /// Remove the [`Record`] under `k` and return it
+    fn remove(&mut self, k: &K) -> io::Result<Option<Record<V>>>;
+
+    /// Every key and [`Record`] held by the backend
+    fn records(&self) -> Vec<(K, Record<V>)>;
+}
+
+/// [`StorageBackend`] that only lives as long as the process
+pub struct MemoryBackend<K, V>(HashMap<K, Record<V>>);
+
+impl<K, V> MemoryBackend<K, V> {
+    pub fn new() -> Self {
+        Self(HashMap::new())
+    }
+
+    /// The number of records in the `MemoryBackend`
+    pub fn size(&self) -> usize {
+        self.0.len()
+    }
+}
+
+impl<K, V> Default for MemoryBackend<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> StorageBackend<K, V> for MemoryBackend<K, V>
+where
+    K: Hash + Eq + Clone + Send + Sync,
+    V: Clone + Send + Sync,
+{
+    fn put(&mut self, k: K, record: Record<V>) -> io::Result<()> {
+        self.0.insert(k, record);
+        Ok(())
+    }
+
+    fn get(&self, k: &K) -> Option<Record<V>> {
+        self.0.get(k).cloned()
+    }
+
+    fn remove(&mut self, k: &K) -> io::Result<Option<Record<V>>> {
+        Ok(self.0.remove(k))
+    }
+
+    fn records(&self) -> Vec<(K, Record<V>)> {
+        self.0.iter().map(|(k, r)| (k.clone(), r.clone())).collect()
+    }
+}
+
+/// A single line of a [`FileBackend`] log
+#[derive(Serialize, Deserialize)]
+enum LogEntry<K, V> {
+    Put { key: K, record: Record<V> },
+    Remove { key: K },
+}
+
+/// Durable [`StorageBackend`] backed by an append-only log of JSON lines. Records are indexed in memory
+/// and the log is rewritten with only live records once it grows to twice their number
+pub struct FileBackend<K, V> {
+    path: PathBuf,
+    log: File,
+    index: MemoryBackend<K, V>,
+    entries: usize,
+}
+
+impl<K, V> FileBackend<K, V>
+where
+    K: Serialize + DeserializeOwned + Hash + Eq + Clone + Send + Sync,
+    V: Serialize + DeserializeOwned + Clone + Send + Sync,
+{
+    /// Open the log at `path`, replaying it to rebuild the index. A torn final line left by a crash is ignored,
+    /// any other line that does not parse is an error so that the records after it are never compacted away
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut index = MemoryBackend::new();
+        let mut entries = 0;
+
+        if path.exists() {
+            let mut lines = BufReader::new(File::open(&path)?)
+                .lines()
+                .enumerate()
+                .peekable();
+            while let Some((number, line)) = lines.next() {
+                let line = line?;
+                match serde_json::from_str::<LogEntry<K, V>>(&line) {
+                    Ok(LogEntry::Put { key, record }) => index.put(key, record)?,
+                    Ok(LogEntry::Remove { key }) => {
+                        index.remove(&key)?;
+                    }
+                    Err(_) if lines.peek().is_none() => break,
+                    Err(e) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("corrupt log entry on line {}: {}", number + 1, e),
+                        ))
+                    }
+                }
+                entries += 1;
+            }
+        }
+
+        let log = OpenOptions::new().create(true).append(true).open(&path)?;
+        let mut backend = Self {
+            path,
+            log,
+            index,
+            entries,
+        };
+        // Rewriting also drops any torn line so later appends start on a clean line
+        backend.compact()?;
+        Ok(backend)
+    }
+
+    /// Rewrite the log so that it only holds the live records
+    pub fn compact(&mut self) -> io::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        {
+            let mut file = File::create(&tmp)?;
+            for (key, record) in self.index.records() {
+                let line = serde_json::to_string(&LogEntry::Put { key, record })?;
+                writeln!(file, "{}", line)?;
+            }
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, &self.path)?;
+
+        self.log = OpenOptions::new().append(true).open(&self.path)?;
+        self.entries = self.index.size();
+        Ok(())
+    }
+
+    fn append(&mut self, entry: &LogEntry<K, V>) -> io::Result<()> {
+        let line = serde_json::to_string(entry)?;
+        writeln!(self.log, "{}", line)?;
+        self.log.sync_data()?;
+        self.entries += 1;
+
+        if self.entries >= COMPACTION_THRESHOLD && self.entries > 2 * self.index.size() {
+            self.compact()?;
+        }
+        Ok(())
+    }
+}
+
+impl<K, V> StorageBackend<K, V> for FileBackend<K, V>
+where
+    K: Serialize + DeserializeOwned + Hash + Eq + Clone + Send + Sync,
+    V: Serialize + DeserializeOwned + Clone + Send + Sync,
+{
+    fn put(&mut self, k: K, record: Record<V>) -> io::Result<()> {
+        self.index.put(k.clone(), record.clone())?;
+        self.append(&LogEntry::Put { key: k, record })
+    }
+
+    fn get(&self, k: &K) -> Option<Record<V>> {
+        self.index.get(k)
+    }
+
+    fn remove(&mut self, k: &K) -> io::Result<Option<Record<V>>> {
+        let record = self.index.remove(k)?;
+        if record.is_some() {
+            self.append(&LogEntry::Remove { key: k.clone() })?;
+        }
+        Ok(record)
+    }
+
+    fn records(&self) -> Vec<(K, Record<V>)> {
+        self.index.records()
+    }
+}
+
+/// Bytes a value counts towards [`StoreLimits`]
+pub trait Weigh {
+    fn weight(&self) -> usize;
+}
+
+impl Weigh for Vec<u8> {
+    fn weight(&self) -> usize {
+        self.len()
+    }
+}
+
+/// Running totals of what a [`Store`] holds, so admitting a record never has to walk every record
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Usage {
+    pub records: usize,
+    pub bytes: usize,
+    /// Bytes stored per [`Record::origin`]
+    pub per_source: HashMap<IpAddr, usize>,
+}
+
+impl Usage {
+    fn add(&mut self, weight: usize, origin: Option<IpAddr>) {
+        self.records += 1;
+        self.bytes += weight;
+        if let Some(origin) = origin {
+            *self.per_source.entry(origin).or_default() += weight;
+        }
+    }
+
+    fn subtract(&mut self, weight: usize, origin: Option<IpAddr>) {
+        self.records -= 1;
+        self.bytes -= weight;
+        if let Some(origin) = origin {
+            if let Some(bytes) = self.per_source.get_mut(&origin) {
+                *bytes -= weight;
+                if *bytes == 0 {
+                    self.per_source.remove(&origin);
+                }
+            }
+        }
+    }
+}
+
 pub struct Store<K, V> {
-    store: HashMap<K, V>,
-    times: BTreeMap<K, Instant>,
+    backend: Box<dyn StorageBackend<K, V>>,
+    usage: Usage,
 }
 
 impl<K, V> Store<K, V>
 where
-    K: Hash + PartialEq + Eq + Ord + Clone + Send + Sync,
-    V: Clone + Send + Sync,
+    K: Hash + PartialEq + Eq + Ord + Clone + Send + Sync + 'static,
+    V: Weigh + Clone + Send + Sync + 'static,
 {
-    /// Create an empty `Store`
+    /// Create an empty `Store` held in memory
     pub fn new() -> Self {
-        Self {
-            store: HashMap::new(),
-            times: BTreeMap::new(),
+        Self::with_backend(Box::new(MemoryBackend::new()))
+    }
+
+    /// Create a `Store` on top of a [`StorageBackend`], totalling up the records it already holds
+    pub fn with_backend(backend: Box<dyn StorageBackend<K, V>>) -> Self {
+        let mut usage = Usage::default();
+        for (_, record) in backend.records() {
+            usage.add(record.value.weight(), record.origin);
         }
+        Self { backend, usage }
+    }
+
+    /// Upsert a [`Record`] to the `Store`
+    pub fn upsert(&mut self, k: K, record: Record<V>) -> io::Result<()> {
+        let existing = self.backend.get(&k).map(|r| (r.value.weight(), r.origin));
+        let added = (record.value.weight(), record.origin);
+        self.backend.put(k, record)?;
+        if let Some((weight, origin)) = existing {
+            self.usage.subtract(weight, origin);
+        }
+        self.usage.add(added.0, added.1);
+        Ok(())
+    }
+
+    /// Totals of every record held, including expired ones not removed yet
+    pub fn usage(&self) -> &Usage {
+        &self.usage
     }
 
-    /// Upsert a value to the `Store`
-    pub fn upsert(&mut self, k: K, v: V) {
-        self.store.insert(k.clone(), v);
-        self.times.insert(k, Instant::now());
+    /// Fetch value and the insertion [`SystemTime`] from the `Store`, expired values are never returned
+    pub fn get(&self, k: &K) -> Option<(V, SystemTime)> {
+        self.record(k).map(|r| (r.value, r.timestamp))
     }
 
-    /// Fetch value and the insertion [`Instant`] from the `Store`
-    pub fn get(&self, k: &K) -> Option<(V, Instant)> {
-        let v = self.store.get(&k).map(|v| v.clone());
-        let t = self.times.get(k).map(|t| t.clone());
-        Option::zip(v, t)
+    /// Fetch the whole [`Record`] under `k` from the `Store`, expired records are never returned
+    pub fn record(&self, k: &K) -> Option<Record<V>> {
+        self.backend
+            .get(k)
+            .filter(|r| !r.is_expired(SystemTime::now()))
     }
 
-    /// Remove all stale entries from the `Store`
-    pub fn remove_stale(&mut self) {
-        let now = Instant::now();
-        let mut keys = Vec::new();
+    /// Every key and unexpired [`Record`] in the `Store`
+    pub fn records(&self) -> Vec<(K, Record<V>)> {
+        let now = SystemTime::now();
+        self.backend
+            .records()
+            .into_iter()
+            .filter(|(_, r)| !r.is_expired(now))
+            .collect()
+    }
 
-        for (k, t) in self.times.iter() {
-            if now.duration_since(*t) >= STALE_DURATION {
-                keys.push(k.clone());
+    /// Upsert a [`Record`] received from another node. Another copy of the value already stored never
+    /// shortens its life or demotes it to a cached copy, so short-lived copies held far from the key cannot age
+    /// out the copies on the closest nodes
+    pub fn upsert_replica(&mut self, k: K, mut record: Record<V>) -> io::Result<()>
+    where
+        V: PartialEq,
+    {
+        if let Some(existing) = self.record(&k) {
+            if existing.value == record.value {
+                record.expires = record.expires.max(existing.expires);
+                record.cached &= existing.cached;
             }
         }
+        self.upsert(k, record)
+    }
+
+    /// Remove all expired entries from the `Store`
+    pub fn remove_stale(&mut self) -> io::Result<()> {
+        let now = SystemTime::now();
 
-        for k in keys {
-            self.times
-                .remove(&k)
-                .expect("failed to remove stale key from times");
-            self.store
-                .remove(&k)
-                .expect("failed to remove stale key from store");
+        for (k, r) in self.backend.records() {
+            if r.is_expired(now) {
+                if let Some(removed) = self.backend.remove(&k)? {
+                    self.usage.subtract(removed.value.weight(), removed.origin);
+                }
+            }
         }
+
+        Ok(())
+    }
+}
+
+impl<K> Store<K, Vec<u8>>
+where
+    K: Hash + PartialEq + Eq + Ord + Clone + Send + Sync + 'static,
+{
+    /// Check whether upserting `value` under `k` sent from the IP address `source` stays within `limits`. A
+    /// value replacing the one already under `k` only counts once
+    pub fn admit(
+        &self,
+        k: &K,
+        value: &[u8],
+        source: IpAddr,
+        limits: &StoreLimits,
+    ) -> Result<(), Rejection> {
+        if value.len() > limits.max_value_bytes {
+            return Err(Rejection::ValueTooLarge);
+        }
+
+        let usage = &self.usage;
+        let (mut records, mut total) = (usage.records + 1, usage.bytes + value.len());
+        let mut from_source = usage.per_source.get(&source).copied().unwrap_or(0) + value.len();
+        if let Some(existing) = self.backend.get(k) {
+            records -= 1;
+            total -= existing.value.len();
+            if existing.origin == Some(source) {
+                from_source -= existing.value.len();
+            }
+        }
+
+        if records > limits.max_records || total > limits.max_total_bytes {
+            return Err(Rejection::StoreFull);
+        }
+        if from_source > limits.max_source_bytes {
+            return Err(Rejection::QuotaExceeded);
+        }
+        Ok(())
+    }
+}
+
+impl Store<Id, Vec<u8>> {
+    /// Check that a record in `namespace` may be stored under `k`. A key holding a namespaced record stays in
+    /// that namespace, so a plain record or one from another namespace cannot skip its validator to replace it.
+    /// A namespaced record may replace a plain one, as its validator vouches for it
+    pub fn bind(&self, k: &Id, namespace: Option<&str>) -> Result<(), Rejection> {
+        match self.record(k).and_then(|r| r.namespace) {
+            Some(bound) if Some(bound.as_str()) != namespace => Err(Rejection::Invalid {
+                reason: format!("key holds a record in namespace {}", bound),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check `value` with the `validator` of its namespace, and that it is preferred over the namespaced value
+    /// already under `k` if there is one
+    pub fn check(
+        &self,
+        k: &Id,
+        value: &[u8],
+        validator: &dyn RecordValidator,
+    ) -> Result<(), Rejection> {
+        validator
+            .validate(k, value)
+            .map_err(|reason| Rejection::Invalid { reason })?;
+
+        match self.record(k) {
+            Some(existing) if existing.namespace.is_some() && existing.value != value => {
+                match validator.select(k, &[existing.value, value.to_vec()]) {
+                    0 => Err(Rejection::Superseded),
+                    _ => Ok(()),
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<K, V> Default for Store<K, V>
+where
+    K: Hash + PartialEq + Eq + Ord + Clone + Send + Sync + 'static,
+    V: Weigh + Clone + Send + Sync + 'static,
+{
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::id::Id;
+
+    impl Weigh for usize {
+        fn weight(&self) -> usize {
+            std::mem::size_of::<usize>()
+        }
+    }
+
+    fn log_path() -> PathBuf {
+        std::env::temp_dir().join(format!("kademlia-{}.log", Id::random().hex()))
+    }
 
     #[test]
     fn upsert() {
         let mut store = Store::<usize, usize>::new();
-        store.upsert(0, 0);
+        store.upsert(0, Record::new(0, STALE_DURATION)).unwrap();
         assert_eq!(store.get(&0).unwrap().0, 0);
         // store.upsert(0, 0);
     }
+
+    #[test]
+    fn expiry() {
+        let mut store = Store::<usize, usize>::new();
+        store.upsert(0, Record::new(0, Duration::ZERO)).unwrap();
+        store.upsert(1, Record::new(1, STALE_DURATION)).unwrap();
+        assert_eq!(store.get(&0), None);
+        assert_eq!(store.get(&1).unwrap().0, 1);
+
+        assert_eq!(store.records().len(), 1);
+        assert_eq!(store.backend.records().len(), 2);
+        store.remove_stale().unwrap();
+        assert_eq!(store.backend.records().len(), 1);
+    }
+
+    fn from(value: Vec<u8>, origin: IpAddr) -> Record<Vec<u8>> {
+        Record {
+            origin: Some(origin),
+            ..Record::new(value, STALE_DURATION)
+        }
+    }
+
+    /// Accepts values starting with a version byte and prefers the highest version
+    struct Versioned;
+
+    impl RecordValidator for Versioned {
+        fn validate(&self, _key: &Id, value: &[u8]) -> Result<(), String> {
+            match value.is_empty() {
+                true => Err("missing version".to_string()),
+                false => Ok(()),
+            }
+        }
+
+        fn select(&self, _key: &Id, values: &[Vec<u8>]) -> usize {
+            (0..values.len()).max_by_key(|&i| values[i][0]).unwrap()
+        }
+    }
+
+    #[test]
+    fn upsert_replica() {
+        let mut store = Store::<usize, usize>::new();
+        store.upsert(0, Record::new(0, STALE_DURATION)).unwrap();
+        let expires = store.record(&0).unwrap().expires;
+
+        let replica = Record {
+            cached: true,
+            ..Record::new(0, Duration::from_secs(60))
+        };
+        store.upsert_replica(0, replica.clone()).unwrap();
+        let record = store.record(&0).unwrap();
+        assert!(record.expires >= expires);
+        assert!(!record.cached);
+
+        // A new value replaces the record outright
+        store
+            .upsert_replica(
+                0,
+                Record {
+                    value: 1,
+                    ..replica
+                },
+            )
+            .unwrap();
+        assert!(store.record(&0).unwrap().expires < expires);
+    }
+
+    fn versioned(value: Vec<u8>) -> Record<Vec<u8>> {
+        Record {
+            namespace: Some("/v/".to_string()),
+            ..Record::new(value, STALE_DURATION)
+        }
+    }
+
+    #[test]
+    fn bind() {
+        let key = Id::random();
+        let mut store = Store::<Id, Vec<u8>>::new();
+        assert_eq!(store.bind(&key, None), Ok(()));
+
+        // A plain record does not hold its key against a validated one
+        store
+            .upsert(key.clone(), Record::new(vec![0], STALE_DURATION))
+            .unwrap();
+        assert_eq!(store.bind(&key, Some("/v/")), Ok(()));
+        assert_eq!(store.check(&key, &[1], &Versioned), Ok(()));
+
+        // A namespace-less store cannot replace a validated record, nor can one from another namespace
+        store.upsert(key.clone(), versioned(vec![1])).unwrap();
+        assert!(matches!(
+            store.bind(&key, None),
+            Err(Rejection::Invalid { .. })
+        ));
+        assert!(matches!(
+            store.bind(&key, Some("/other/")),
+            Err(Rejection::Invalid { .. })
+        ));
+        assert_eq!(store.bind(&key, Some("/v/")), Ok(()));
+    }
+
+    #[test]
+    fn check() {
+        let key = Id::random();
+        let mut store = Store::<Id, Vec<u8>>::new();
+        assert!(matches!(
+            store.check(&key, &[], &Versioned),
+            Err(Rejection::Invalid { .. })
+        ));
+        assert_eq!(store.check(&key, &[2], &Versioned), Ok(()));
+        store.upsert(key.clone(), versioned(vec![2])).unwrap();
+        assert_eq!(
+            store.check(&key, &[1], &Versioned),
+            Err(Rejection::Superseded)
+        );
+        assert_eq!(store.check(&key, &[2], &Versioned), Ok(()));
+        assert_eq!(store.check(&key, &[3], &Versioned), Ok(()));
+    }
+
+    #[test]
+    fn admit() {
+        let limits = StoreLimits {
+            max_value_bytes: 10,
+            max_total_bytes: 25,
+            max_records: 3,
+            max_source_bytes: 15,
+        };
+        let (a, b): (IpAddr, IpAddr) = ("1.1.1.1".parse().unwrap(), "2.2.2.2".parse().unwrap());
+        let mut store = Store::<usize, Vec<u8>>::new();
+
+        assert_eq!(
+            store.admit(&0, &[0; 11], a, &limits),
+            Err(Rejection::ValueTooLarge)
+        );
+        store.upsert(0, from(vec![0; 10], a)).unwrap();
+        assert_eq!(
+            store.admit(&1, &[0; 6], a, &limits),
+            Err(Rejection::QuotaExceeded)
+        );
+        assert_eq!(store.admit(&0, &[0; 10], a, &limits), Ok(()));
+        store.upsert(1, from(vec![0; 10], b)).unwrap();
+        assert_eq!(
+            store.admit(&2, &[0; 6], b, &limits),
+            Err(Rejection::StoreFull)
+        );
+        store
+            .upsert(2, Record::new(vec![0; 1], STALE_DURATION))
+            .unwrap();
+        assert_eq!(
+            store.admit(&3, &[0; 1], b, &limits),
+            Err(Rejection::StoreFull)
+        );
+
+        // Totals follow replacements and removals
+        store.upsert(1, from(vec![0; 4], b)).unwrap();
+        store
+            .upsert(3, Record::new(vec![0; 2], Duration::ZERO))
+            .unwrap();
+        store.remove_stale().unwrap();
+        assert_eq!(store.usage().records, 3);
+        assert_eq!(store.usage().bytes, 15);
+        assert_eq!(store.usage().per_source.get(&b), Some(&4));
+    }
+
+    #[test]
+    fn scaling() {
+        assert_eq!(scaled_ttl(STALE_DURATION, 0), STALE_DURATION);
+        assert_eq!(
+            scaled_ttl(STALE_DURATION, KBUCKET_MAX_LENGTH - 1),
+            STALE_DURATION
+        );
+        assert_eq!(
+            scaled_ttl(STALE_DURATION, KBUCKET_MAX_LENGTH),
+            STALE_DURATION / 2
+        );
+        assert_eq!(
+            scaled_ttl(STALE_DURATION, 3 * KBUCKET_MAX_LENGTH),
+            STALE_DURATION / 8
+        );
+    }
+
+    #[test]
+    fn file_backend_reopen() {
+        let path = log_path();
+        let timestamp = {
+            let mut backend = FileBackend::<String, String>::open(&path).unwrap();
+            backend
+                .put(
+                    "hello".to_string(),
+                    Record::new("world".to_string(), STALE_DURATION),
+                )
+                .unwrap();
+            backend
+                .put(
+                    "gone".to_string(),
+                    Record::new("soon".to_string(), STALE_DURATION),
+                )
+                .unwrap();
+            backend.remove(&"gone".to_string()).unwrap();
+            backend.get(&"hello".to_string()).unwrap().timestamp
+        };
+
+        let backend = FileBackend::<String, String>::open(&path).unwrap();
+        let record = backend.get(&"hello".to_string()).unwrap();
+        assert_eq!(record.value, "world");
+        assert_eq!(record.timestamp, timestamp);
+        assert_eq!(backend.get(&"gone".to_string()), None);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn file_backend_corruption() {
+        let path = log_path();
+        {
+            let mut backend = FileBackend::<usize, usize>::open(&path).unwrap();
+            backend.put(0, Record::new(0, STALE_DURATION)).unwrap();
+            backend.put(1, Record::new(1, STALE_DURATION)).unwrap();
+        }
+
+        // A write torn by a crash only loses the last entry
+        let log = fs::read_to_string(&path).unwrap();
+        fs::write(&path, &log[..log.len() - 5]).unwrap();
+        let backend = FileBackend::<usize, usize>::open(&path).unwrap();
+        assert_eq!(backend.get(&0).unwrap().value, 0);
+        assert_eq!(backend.get(&1), None);
+        drop(backend);
+
+        // Damage before the end is refused rather than dropping everything after it
+        let log = fs::read_to_string(&path).unwrap();
+        fs::write(&path, format!("garbage\n{}", log)).unwrap();
+        assert!(FileBackend::<usize, usize>::open(&path).is_err());
+        assert!(fs::read_to_string(&path).unwrap().starts_with("garbage"));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn file_backend_old_entries() {
+        let path = log_path();
+        {
+            let mut backend = FileBackend::<usize, usize>::open(&path).unwrap();
+            backend.put(0, Record::new(0, STALE_DURATION)).unwrap();
+        }
+
+        // Entries written before the optional fields existed still load
+        let mut entry: serde_json::Value =
+            serde_json::from_str(fs::read_to_string(&path).unwrap().trim()).unwrap();
+        let record = entry["Put"]["record"].as_object_mut().unwrap();
+        for field in ["source", "origin", "namespace", "cached"] {
+            record.remove(field).unwrap();
+        }
+        fs::write(&path, format!("{}\n", entry)).unwrap();
+        let backend = FileBackend::<usize, usize>::open(&path).unwrap();
+        let record = backend.get(&0).unwrap();
+        assert_eq!(record.value, 0);
+        assert_eq!(
+            (record.source, record.origin, record.namespace),
+            (None, None, None)
+        );
+        assert!(!record.cached);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn file_backend_compaction() {
+        let path = log_path();
+        let mut backend = FileBackend::<usize, usize>::open(&path).unwrap();
+        for i in 0..COMPACTION_THRESHOLD {
+            backend.put(i % 4, Record::new(i, STALE_DURATION)).unwrap();
+        }
+
+        let lines = fs::read_to_string(&path).unwrap().lines().count();
+        assert_eq!(lines, 4);
+        assert_eq!(backend.get(&3).unwrap().value, COMPACTION_THRESHOLD - 1);
+        fs::remove_file(path).unwrap();
+    }
 }
@@ -0,0 +1,77 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Debug, Formatter},
+    sync::Arc,
+};
+
+use crate::id::Id;
+
+/// Rules for the records stored under a key namespace such as `/pk/` or `/cfg/`
+pub trait RecordValidator: Send + Sync {
+    /// Check that `value` may be stored under `key`, returning why not otherwise
+    fn validate(&self, key: &Id, value: &[u8]) -> Result<(), String>;
+
+    /// Index of the best of several valid, conflicting `values` for `key`. `values` is never empty
+    fn select(&self, key: &Id, values: &[Vec<u8>]) -> usize;
+}
+
+/// [`RecordValidator`]s registered per namespace
+#[derive(Default, Clone)]
+pub struct Validators(HashMap<String, Arc<dyn RecordValidator>>);
+
+impl Validators {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register the [`RecordValidator`] for a namespace, replacing any previous one
+    pub fn register(&mut self, namespace: impl Into<String>, validator: Arc<dyn RecordValidator>) {
+        self.0.insert(namespace.into(), validator);
+    }
+
+    /// The [`RecordValidator`] for a namespace
+    pub fn get(&self, namespace: &str) -> Option<Arc<dyn RecordValidator>> {
+        self.0.get(namespace).cloned()
+    }
+}
+
+impl Debug for Validators {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Accepts values up to 4 bytes and prefers the longest
+    struct Short;
+
+    impl RecordValidator for Short {
+        fn validate(&self, _key: &Id, value: &[u8]) -> Result<(), String> {
+            match value.len() {
+                0..=4 => Ok(()),
+                _ => Err("too long".to_string()),
+            }
+        }
+
+        fn select(&self, _key: &Id, values: &[Vec<u8>]) -> usize {
+            (0..values.len()).max_by_key(|&i| values[i].len()).unwrap()
+        }
+    }
+
+    #[test]
+    fn register() {
+        let mut validators = Validators::new();
+        validators.register("/short/", Arc::new(Short));
+        assert!(validators.get("/long/").is_none());
+
+        let key = Id::random();
+        let short = validators.get("/short/").unwrap();
+        assert!(short.validate(&key, b"abcd").is_ok());
+        assert!(short.validate(&key, b"abcde").is_err());
+        assert_eq!(short.select(&key, &[b"a".to_vec(), b"abc".to_vec()]), 1);
+        assert_eq!(format!("{:?}", validators), r#"{"/short/"}"#);
+    }
+}